@@ -0,0 +1,296 @@
+use rand::Rng;
+use shakmaty::{
+    uci::UciMove,
+    zobrist::{Zobrist64, ZobristHash},
+    Chess, EnPassantMode, Move, Role, Square,
+};
+use std::{fs, io};
+
+/// Entry of a Polyglot book
+struct BookEntry {
+    key: u64,
+    move_: u16,
+    weight: u16,
+}
+
+/// Polyglot opening book (`.bin`)
+/// http://hgm.nubati.net/book_format.html
+/// --------------------------------
+/// The file is a list of 16 byte entries sorted by key:
+/// key (64) | move (16) | weight (16) | learn (32), all big endian
+pub struct Book {
+    entries: Vec<BookEntry>,
+}
+
+impl Book {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::from_memory(&fs::read(path)?)
+    }
+
+    pub fn from_memory(buffer: &[u8]) -> io::Result<Self> {
+        if buffer.len() % 16 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Polyglot books are made of 16 byte entries",
+            ));
+        }
+
+        let entries = buffer
+            .chunks_exact(16)
+            .map(|chunk| BookEntry {
+                key: u64::from_be_bytes(chunk[0..8].try_into().unwrap()),
+                move_: u16::from_be_bytes(chunk[8..10].try_into().unwrap()),
+                weight: u16::from_be_bytes(chunk[10..12].try_into().unwrap()),
+            })
+            .collect::<Vec<_>>();
+
+        if !entries.is_sorted_by_key(|entry| entry.key) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Polyglot book entries are not sorted",
+            ));
+        }
+
+        Ok(Book { entries })
+    }
+
+    /// Number of entries in the book
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Book moves of the position with their weights
+    pub fn moves(&self, pos: &Chess) -> Vec<(Move, u16)> {
+        let key = polyglot_key(pos);
+        let start = self.entries.partition_point(|entry| entry.key < key);
+
+        self.entries[start..]
+            .iter()
+            .take_while(|entry| entry.key == key)
+            .filter_map(|entry| Some((unpack_move(entry.move_, pos)?, entry.weight)))
+            .collect()
+    }
+
+    /// Picks a random book move, weighted by the entry weights
+    pub fn pick_move(&self, pos: &Chess) -> Option<Move> {
+        let moves = self.moves(pos);
+        let total = moves.iter().map(|(_, weight)| *weight as u32).sum::<u32>();
+
+        if total == 0 {
+            return None;
+        }
+
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for (mov, weight) in moves {
+            if pick < weight as u32 {
+                return Some(mov);
+            }
+            pick -= weight as u32;
+        }
+
+        unreachable!()
+    }
+}
+
+/// Polyglot key of the position
+/// The Zobrist hashes of shakmaty use the Polyglot random numbers, so it is the same as `PositionStack::hash_key`,
+/// except for the en passant square: Polyglot includes it if there is a pawn next to the pushed pawn,
+/// even if the capture is not legal
+pub fn polyglot_key(pos: &Chess) -> u64 {
+    pos.zobrist_hash::<Zobrist64>(EnPassantMode::PseudoLegal).0
+}
+
+/// Unpacks a Polyglot move, only if it is legal in the given position
+/// to file (3) | to row (3) | from file (3) | from row (3) | promotion (3)
+/// Castling moves are stored as king to rook
+fn unpack_move(packed: u16, pos: &Chess) -> Option<Move> {
+    let promotion = (packed >> 12) as usize & 0b111;
+
+    UciMove::Normal {
+        from: Square::new((packed >> 6 & 0b111111) as u32),
+        to: Square::new((packed & 0b111111) as u32),
+        // knight, bishop, rook, queen
+        promotion: (promotion > 0).then(|| Role::ALL[promotion]),
+    }
+    .to_move(pos)
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode, Position};
+
+    fn play(moves: &[&str]) -> Chess {
+        moves.iter().fold(Chess::default(), |pos, uci| {
+            let mov = uci.parse::<UciMove>().unwrap().to_move(&pos).unwrap();
+            pos.play(&mov).unwrap()
+        })
+    }
+
+    #[test]
+    fn test_polyglot_keys() {
+        // keys from the Polyglot specification
+        let cases: [(&[&str], u64); 9] = [
+            (&[], 0x463b96181691fc9c),
+            (&["e2e4"], 0x823c9b50fd114196),
+            (&["e2e4", "d7d5"], 0x0756b94461c50fb0),
+            (&["e2e4", "d7d5", "e4e5"], 0x662fafb965db29d4),
+            (&["e2e4", "d7d5", "e4e5", "f7f5"], 0x22a48b5a8e47ff78),
+            (
+                &["e2e4", "d7d5", "e4e5", "f7f5", "e1e2"],
+                0x652a607ca3f242c1,
+            ),
+            (
+                &["e2e4", "d7d5", "e4e5", "f7f5", "e1e2", "e8f7"],
+                0x00fdd303c946bdd9,
+            ),
+            (
+                &["a2a4", "b7b5", "h2h4", "b5b4", "c2c4"],
+                0x3c8123ea7b067637,
+            ),
+            (
+                &["a2a4", "b7b5", "h2h4", "b5b4", "c2c4", "b4c3", "a1a3"],
+                0x5c3f9b829b279560,
+            ),
+        ];
+
+        for (moves, key) in cases {
+            assert_eq!(polyglot_key(&play(moves)), key, "{:?}", moves);
+        }
+    }
+
+    /// Walks lines move by move, the key must match the one of the same position read from a FEN
+    /// (and the Polyglot reference key, where known), so castling rights and en passant are kept right
+    #[test]
+    fn test_polyglot_lines() {
+        let lines: [&[(&str, &str, Option<u64>)]; 2] = [
+            &[
+                (
+                    "e2e4",
+                    "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
+                    Some(0x823c9b50fd114196),
+                ),
+                // en passant square with a pawn next to the pushed one
+                (
+                    "d7d5",
+                    "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 2",
+                    Some(0x0756b94461c50fb0),
+                ),
+                (
+                    "e4e5",
+                    "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 2",
+                    Some(0x662fafb965db29d4),
+                ),
+                (
+                    "f7f5",
+                    "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+                    Some(0x22a48b5a8e47ff78),
+                ),
+                // king moves lose both castling rights
+                (
+                    "e1e2",
+                    "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPPKPPP/RNBQ1BNR b kq - 1 3",
+                    Some(0x652a607ca3f242c1),
+                ),
+                (
+                    "e8f7",
+                    "rnbq1bnr/ppp1pkpp/8/3pPp2/8/8/PPPPKPPP/RNBQ1BNR w - - 2 4",
+                    Some(0x00fdd303c946bdd9),
+                ),
+            ],
+            &[
+                (
+                    "a2a4",
+                    "rnbqkbnr/pppppppp/8/8/P7/8/1PPPPPPP/RNBQKBNR b KQkq a3 0 1",
+                    None,
+                ),
+                (
+                    "b7b5",
+                    "rnbqkbnr/p1pppppp/8/1p6/P7/8/1PPPPPPP/RNBQKBNR w KQkq b6 0 2",
+                    None,
+                ),
+                (
+                    "h2h4",
+                    "rnbqkbnr/p1pppppp/8/1p6/P6P/8/1PPPPPP1/RNBQKBNR b KQkq h3 0 2",
+                    None,
+                ),
+                (
+                    "b5b4",
+                    "rnbqkbnr/p1pppppp/8/8/Pp5P/8/1PPPPPP1/RNBQKBNR w KQkq - 0 3",
+                    None,
+                ),
+                (
+                    "c2c4",
+                    "rnbqkbnr/p1pppppp/8/8/PpP4P/8/1P1PPPP1/RNBQKBNR b KQkq c3 0 3",
+                    Some(0x3c8123ea7b067637),
+                ),
+                // en passant capture
+                (
+                    "b4c3",
+                    "rnbqkbnr/p1pppppp/8/8/P6P/2p5/1P1PPPP1/RNBQKBNR w KQkq - 0 4",
+                    None,
+                ),
+                // rook moves lose the castling right of their side
+                (
+                    "a1a3",
+                    "rnbqkbnr/p1pppppp/8/8/P6P/R1p5/1P1PPPP1/1NBQKBNR b Kkq - 1 4",
+                    Some(0x5c3f9b829b279560),
+                ),
+            ],
+        ];
+
+        for line in lines {
+            let mut pos = Chess::default();
+            assert_eq!(polyglot_key(&pos), 0x463b96181691fc9c);
+
+            for &(uci, fen, reference) in line {
+                let mov = uci.parse::<UciMove>().unwrap().to_move(&pos).unwrap();
+                pos = pos.play(&mov).unwrap();
+
+                let expected = fen
+                    .parse::<Fen>()
+                    .unwrap()
+                    .into_position::<Chess>(CastlingMode::Standard)
+                    .unwrap();
+                assert_eq!(polyglot_key(&pos), polyglot_key(&expected), "{}", uci);
+                if let Some(reference) = reference {
+                    assert_eq!(polyglot_key(&pos), reference, "{}", uci);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_book_moves() {
+        let start = polyglot_key(&Chess::default());
+
+        let mut buffer = Vec::new();
+        for (key, packed, weight) in [
+            (start, 0b000_001_100_011_100u16, 3), // e2e4
+            (start, 0b000_001_011_011_011u16, 1), // d2d4
+            (start + 1, 0b000_001_010_011_010u16, 1),
+        ] {
+            buffer.extend(key.to_be_bytes());
+            buffer.extend(packed.to_be_bytes());
+            buffer.extend((weight as u16).to_be_bytes());
+            buffer.extend(0u32.to_be_bytes());
+        }
+
+        let book = Book::from_memory(&buffer).unwrap();
+        let moves = book
+            .moves(&Chess::default())
+            .into_iter()
+            .map(|(mov, weight)| {
+                (
+                    mov.to_uci(shakmaty::CastlingMode::Standard).to_string(),
+                    weight,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(moves, [("e2e4".to_string(), 3), ("d2d4".to_string(), 1)]);
+        assert!(book.pick_move(&Chess::default()).is_some());
+        assert!(book.pick_move(&play(&["e2e4"])).is_none());
+    }
+}
@@ -9,6 +9,24 @@ pub const INFINITY: Value = 50_000;
 /// Maximum number of plies the engine supports
 pub const MAX_PLY: usize = 64;
 
+/// Score of being checkmated at the root, a mate found at ply N is scored as `-MATE + N`
+pub const MATE: Value = 10_000;
+
+/// Scores beyond this value (in absolute value) are mate scores
+pub const MATE_THRESHOLD: Value = MATE - MAX_PLY as Value;
+
+/// Converts a mate score into the number of moves until mate,
+/// negative if the side to move is getting mated
+pub fn mate_in(score: Value) -> Option<i32> {
+    if score >= MATE_THRESHOLD {
+        Some((MATE - score + 1) / 2)
+    } else if score <= -MATE_THRESHOLD {
+        Some(-(MATE + score) / 2)
+    } else {
+        None
+    }
+}
+
 /// Invalid move for initialization
 pub const INVALID_MOVE: Move = Move::Normal {
     role: Role::Pawn,
@@ -20,3 +38,29 @@ pub const INVALID_MOVE: Move = Move::Normal {
 
 /// Hash key type
 pub type HashKey = Zobrist64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mate_in() {
+        // mating: the number of moves is rounded up
+        assert_eq!(mate_in(MATE - 1), Some(1));
+        assert_eq!(mate_in(MATE - 2), Some(1));
+        assert_eq!(mate_in(MATE - 3), Some(2));
+        assert_eq!(mate_in(MATE - 4), Some(2));
+        assert_eq!(mate_in(MATE_THRESHOLD), Some((MAX_PLY as i32 + 1) / 2));
+
+        // getting mated: negative
+        assert_eq!(mate_in(-MATE), Some(0));
+        assert_eq!(mate_in(-MATE + 2), Some(-1));
+        assert_eq!(mate_in(-MATE + 4), Some(-2));
+        assert_eq!(mate_in(-MATE_THRESHOLD), Some(-(MAX_PLY as i32) / 2));
+
+        // not a mate
+        assert_eq!(mate_in(0), None);
+        assert_eq!(mate_in(MATE_THRESHOLD - 1), None);
+        assert_eq!(mate_in(-MATE_THRESHOLD + 1), None);
+    }
+}
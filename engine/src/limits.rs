@@ -1,9 +1,10 @@
 use std::time::Duration;
 
-use shakmaty::Color;
+use shakmaty::{uci::UciMove, Chess, Color, Move, Position, Role};
 use vampirc_uci::{UciSearchControl, UciTimeControl};
 
 /// Search termination conditions
+#[derive(Clone)]
 pub struct SearchLimits {
     /// Depth limit, do not exceed this depth
     pub depth: Option<i32>,
@@ -11,6 +12,20 @@ pub struct SearchLimits {
     pub nodes: Option<usize>,
     /// Time limit, do not search for longer than this
     pub time: Option<Duration>,
+    /// Soft time limit, do not start a new iteration after this
+    /// It is scaled during the search depending on how stable the best move is
+    pub soft_time: Option<Duration>,
+    /// Mate limit, stop when a mate in this many moves (or less) is found
+    pub mate: Option<i32>,
+    /// Search until `stop` is received, even if the search is over
+    pub infinite: bool,
+    /// Search on the opponent's time, the limits only apply after `ponderhit`
+    pub ponder: bool,
+    /// Number of best lines to search (MultiPV)
+    pub multipv: usize,
+    /// Only search these moves at the root, all legal moves if None.
+    /// It is empty if `searchmoves` was given but none of the moves is legal
+    pub search_moves: Option<Vec<Move>>,
 }
 
 impl SearchLimits {
@@ -20,6 +35,12 @@ impl SearchLimits {
             depth: None,
             nodes: None,
             time: None,
+            soft_time: None,
+            mate: None,
+            infinite: false,
+            ponder: false,
+            multipv: 1,
+            search_moves: None,
         }
     }
 
@@ -27,40 +48,59 @@ impl SearchLimits {
     pub fn from_uci(
         time_control: Option<UciTimeControl>,
         search_control: Option<UciSearchControl>,
-        turn: Color,
+        pos: &Chess,
     ) -> Self {
-        let available_time = match time_control {
-            None => None, // infinite
-            Some(UciTimeControl::Infinite) => None,
-            Some(UciTimeControl::Ponder) => unimplemented!(""),
-            Some(UciTimeControl::MoveTime(fixed_time)) => fixed_time.to_std().ok(), // movetime X (ms)
+        let infinite = matches!(time_control, Some(UciTimeControl::Infinite));
+        let ponder = matches!(time_control, Some(UciTimeControl::Ponder));
+
+        let (soft_time, hard_time) = match time_control {
+            None => (None, None), // infinite
+            Some(UciTimeControl::Infinite) => (None, None),
+            Some(UciTimeControl::Ponder) => (None, None), // no clock info, wait for ponderhit or stop
+            Some(UciTimeControl::MoveTime(fixed_time)) => {
+                // movetime X (ms)
+                let time = fixed_time.to_std().ok().map(|t| {
+                    // wiggle room to not time out
+                    if t < Duration::from_millis(500) {
+                        (t - Duration::from_millis(2).min(t)).max(Duration::from_millis(2))
+                    } else {
+                        t - Duration::from_millis(10)
+                    }
+                });
+                (time, time)
+            }
             Some(UciTimeControl::TimeLeft {
                 white_time,
                 black_time,
                 white_increment,
                 black_increment,
-                moves_to_go: _, // TODO: use this
+                moves_to_go,
             }) => {
                 let white_time = white_time.map(|x| x.num_milliseconds()).unwrap_or(0);
                 let black_time = black_time.map(|x| x.num_milliseconds()).unwrap_or(0);
                 let white_incr = white_increment.map(|x| x.num_milliseconds()).unwrap_or(0);
                 let black_incr = black_increment.map(|x| x.num_milliseconds()).unwrap_or(0);
 
-                let (my_time, my_incr) = if turn == Color::White {
+                let (my_time, my_incr) = if pos.turn() == Color::White {
                     (white_time, white_incr)
                 } else {
                     (black_time, black_incr)
                 };
 
-                // basic time management:
-                // move time = increment + time left * 0.02
-                let ms = my_incr as f32 + 0.02 * my_time as f32;
-                Some(Duration::from_millis(ms as u64))
+                let (soft, hard) = allocate_time(
+                    my_time.max(0) as u64,
+                    my_incr.max(0) as u64,
+                    moves_to_go.map(|m| m as u64),
+                    game_phase(pos),
+                );
+                (Some(soft), Some(hard))
             }
         };
 
         let mut max_depth = None;
         let mut max_nodes = None;
+        let mut max_mate = None;
+        let mut search_moves = None;
 
         if let Some(ref search_control) = search_control {
             if let Some(opt_depth) = search_control.depth {
@@ -72,19 +112,163 @@ impl SearchLimits {
                 assert!(opt_nodes >= 1);
                 max_nodes = Some(opt_nodes as usize);
             }
+
+            if let Some(opt_mate) = search_control.mate {
+                assert!(opt_mate >= 1);
+                max_mate = Some(opt_mate as i32);
+            }
+
+            // illegal moves are ignored
+            if !search_control.search_moves.is_empty() {
+                search_moves = Some(
+                    search_control
+                        .search_moves
+                        .iter()
+                        .filter_map(|m| m.to_string().parse::<UciMove>().ok()?.to_move(pos).ok())
+                        .collect(),
+                );
+            }
         }
 
         SearchLimits {
             depth: max_depth,
             nodes: max_nodes,
-            time: available_time.map(|t| {
-                // wiggle room to not time out
-                if t < Duration::from_millis(500) {
-                    (t - Duration::from_millis(2)).max(Duration::from_millis(2))
-                } else {
-                    t - Duration::from_millis(10)
+            time: hard_time,
+            soft_time,
+            mate: max_mate,
+            infinite,
+            ponder,
+            multipv: 1,
+            search_moves,
+        }
+    }
+}
+
+/// Game phase, from 1 at the start (all pieces on the board) to 0 in pawn endgames
+fn game_phase(pos: &Chess) -> f32 {
+    let board = pos.board();
+    let phase = (board.by_role(Role::Knight) | board.by_role(Role::Bishop)).count()
+        + board.by_role(Role::Rook).count() * 2
+        + board.by_role(Role::Queen).count() * 4;
+
+    (phase as f32 / 24.0).min(1.0)
+}
+
+/// Time management
+/// https://www.chessprogramming.org/Time_Management
+/// --------------------------------
+/// Splits the remaining time between the moves left until the next time control
+/// (estimated from the game phase in sudden death), plus most of the increment.
+/// Returns the soft limit (time to spend on the move normally)
+/// and the hard limit (never exceed this, even when the search is unstable).
+fn allocate_time(
+    my_time: u64,
+    my_incr: u64,
+    moves_to_go: Option<u64>,
+    phase: f32,
+) -> (Duration, Duration) {
+    // time lost communicating with the GUI
+    let overhead = (my_time / 10).min(50);
+    let available = my_time.saturating_sub(overhead).max(1);
+
+    // in sudden death, expect more moves while there are pieces on the board
+    let moves_to_go = moves_to_go.unwrap_or((20.0 + 20.0 * phase) as u64).max(1);
+
+    // do not use the whole clock on a single move, unless it is the last before the time control
+    let max_fraction = if moves_to_go == 1 { 0.9 } else { 0.5 };
+    let max_time = (available as f32 * max_fraction) as u64;
+
+    let hard = (available / moves_to_go * 4 + my_incr).min(max_time);
+    let soft = (available / moves_to_go + my_incr * 3 / 4).min(hard);
+
+    (
+        Duration::from_millis(soft.max(1)),
+        Duration::from_millis(hard.max(1)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::CastlingMode;
+    use vampirc_uci::{parse_one, UciMessage};
+
+    /// Limits of a `go` command from the starting position
+    fn go(command: &str) -> SearchLimits {
+        match parse_one(command) {
+            UciMessage::Go {
+                time_control,
+                search_control,
+            } => SearchLimits::from_uci(time_control, search_control, &Chess::default()),
+            _ => panic!("not a go command: {}", command),
+        }
+    }
+
+    /// The root moves to search, as UCI strings
+    fn search_moves(limits: &SearchLimits) -> Option<Vec<String>> {
+        limits.search_moves.as_ref().map(|moves| {
+            moves
+                .iter()
+                .map(|m| m.to_uci(CastlingMode::Standard).to_string())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_soft_below_hard() {
+        for my_time in [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000] {
+            for my_incr in [0, 100, 1_000, 10_000] {
+                for moves_to_go in [None, Some(1), Some(2), Some(40)] {
+                    let (soft, hard) = allocate_time(my_time, my_incr, moves_to_go, 1.0);
+
+                    assert!(soft <= hard);
+                    assert!(!hard.is_zero());
+                    // never more than what is left on the clock
+                    assert!(hard.as_millis() <= my_time.max(1) as u128);
                 }
-            }),
+            }
         }
     }
+
+    #[test]
+    fn test_moves_to_go() {
+        // fewer moves to go, more time per move
+        let (soft_40, _) = allocate_time(60_000, 0, Some(40), 1.0);
+        let (soft_10, _) = allocate_time(60_000, 0, Some(10), 1.0);
+        assert!(soft_10 > soft_40);
+
+        // the last move before the time control can use most of the clock
+        let (_, hard) = allocate_time(10_000, 0, Some(1), 1.0);
+        assert!(hard >= Duration::from_millis(8_000));
+    }
+
+    #[test]
+    fn test_phase() {
+        // sudden death: more time per move in the endgame, since fewer moves are expected
+        let (opening, _) = allocate_time(60_000, 0, None, 1.0);
+        let (endgame, _) = allocate_time(60_000, 0, None, 0.0);
+        assert!(endgame > opening);
+    }
+
+    #[test]
+    fn test_search_moves() {
+        // all moves
+        assert_eq!(search_moves(&go("go depth 5")), None);
+        assert_eq!(search_moves(&go("go infinite")), None);
+
+        // legal moves only
+        assert_eq!(
+            search_moves(&go("go searchmoves e2e4 g1f3")),
+            Some(vec!["e2e4".to_string(), "g1f3".to_string()])
+        );
+
+        // illegal moves are dropped
+        assert_eq!(
+            search_moves(&go("go searchmoves e2e5 g1f3 a1a8")),
+            Some(vec!["g1f3".to_string()])
+        );
+
+        // if none is legal nothing is searched, instead of every move
+        assert_eq!(search_moves(&go("go searchmoves e2e5 a1a8")), Some(vec![]));
+    }
 }
@@ -1,21 +1,37 @@
+mod book;
 mod defs;
 mod limits;
+mod params;
 mod position_stack;
 mod pv_table;
 mod search;
+mod see;
+mod tablebase;
+mod threads;
 mod transposition_table;
 
+use book::Book;
 use clap::Parser;
 use limits::SearchLimits;
 use nn::nnue::model::NnueModel;
-use search::Search;
+use params::SEARCH_PARAMS_OPTIONS;
 use shakmaty::fen::Fen;
 use shakmaty::uci::UciMove;
-use shakmaty::{CastlingMode, Chess, Position};
-use std::cell::RefCell;
+use shakmaty::{CastlingMode, Chess};
 use std::io::{self, BufRead};
-use std::rc::Rc;
-use vampirc_uci::{parse_one, UciMessage};
+use std::sync::Arc;
+use tablebase::Tablebases;
+use threads::{ThreadPool, DEFAULT_HASH_MB};
+use vampirc_uci::{parse_one, UciMessage, UciOptionConfig};
+
+/// Maximum number of search threads
+const MAX_THREADS: usize = 256;
+
+/// Maximum number of lines in MultiPV mode
+const MAX_MULTIPV: usize = 256;
+
+/// Maximum size of the transposition table in MB
+const MAX_HASH_MB: usize = 65536;
 
 #[derive(Parser)]
 struct Cli {
@@ -32,22 +48,39 @@ fn main() {
         NnueModel::load(&path)
     } else {
         println!("info string Using embedded NNUE");
-        NnueModel::from_memory(&include_bytes!("../../models/best.nn").to_vec())
+        Ok(embedded_model())
     }
     .expect("Failed to load NNUE model");
 
     println!("info string NNUE net: {}", model.arch);
     println!("info string NNUE size: {} params", model.params);
+    println!("info string NNUE SIMD: {}", model.simd);
 
-    let model = Rc::new(RefCell::new(model));
-    let mut search = Search::new(model.clone());
+    let mut search = ThreadPool::new(Arc::new(model), 1);
+    let mut own_book = false;
+    let mut book: Option<Arc<Book>> = None;
+    let mut multipv = 1;
+    let mut castling_mode = CastlingMode::Standard;
 
     for line in io::stdin().lock().lines() {
-        let msg: UciMessage = parse_one(&line.unwrap().trim());
+        let line = line.unwrap();
+        let mut tokens = line.split_whitespace().collect::<Vec<_>>();
+
+        // the UCI parser treats `ponder` as the time control of `go`, dropping the clock info
+        // that comes along with it, so the token is removed before parsing
+        let ponder = tokens.first() == Some(&"go") && tokens.contains(&"ponder");
+        tokens.retain(|token| !ponder || *token != "ponder");
+
+        let msg: UciMessage = parse_one(&tokens.join(" "));
 
         match msg {
             UciMessage::IsReady => println!("{}", UciMessage::ReadyOk),
-            UciMessage::Quit => break,
+            UciMessage::Stop => search.stop(),
+            UciMessage::PonderHit => search.ponderhit(),
+            UciMessage::Quit => {
+                search.stop();
+                break;
+            }
             UciMessage::Uci => {
                 println!(
                     "{}",
@@ -56,14 +89,187 @@ fn main() {
                         author: Some("mlomb".to_string())
                     }
                 );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Spin {
+                        name: "Threads".to_string(),
+                        default: Some(1),
+                        min: Some(1),
+                        max: Some(MAX_THREADS as i64),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Spin {
+                        name: "Hash".to_string(),
+                        default: Some(DEFAULT_HASH_MB as i64),
+                        min: Some(1),
+                        max: Some(MAX_HASH_MB as i64),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Button {
+                        name: "Clear Hash".to_string(),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::String {
+                        name: "EvalFile".to_string(),
+                        default: Some("<embedded>".to_string()),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Spin {
+                        name: "MultiPV".to_string(),
+                        default: Some(1),
+                        min: Some(1),
+                        max: Some(MAX_MULTIPV as i64),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Check {
+                        name: "Ponder".to_string(),
+                        default: Some(false),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Check {
+                        name: "UCI_Chess960".to_string(),
+                        default: Some(false),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::Check {
+                        name: "OwnBook".to_string(),
+                        default: Some(false),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::String {
+                        name: "BookFile".to_string(),
+                        default: Some("<empty>".to_string()),
+                    })
+                );
+                println!(
+                    "{}",
+                    UciMessage::Option(UciOptionConfig::String {
+                        name: "SyzygyPath".to_string(),
+                        default: Some("<empty>".to_string()),
+                    })
+                );
+                for (name, default, min, max) in SEARCH_PARAMS_OPTIONS {
+                    println!(
+                        "{}",
+                        UciMessage::Option(UciOptionConfig::Spin {
+                            name: name.to_string(),
+                            default: Some(default as i64),
+                            min: Some(min as i64),
+                            max: Some(max as i64),
+                        })
+                    );
+                }
                 println!("{}", UciMessage::UciOk);
             }
-            UciMessage::UciNewGame => {
-                // reset the search completely
-                // this may be expensive and the object should be reused
-                // but this way I'm sure I'm not leaking anything from the previous game
-                search = Search::new(model.clone());
-            }
+            UciMessage::SetOption { name, value } => match name.as_str() {
+                "Threads" => {
+                    let threads = value
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(1)
+                        .clamp(1, MAX_THREADS);
+                    search.set_threads(threads);
+                }
+                "Hash" => {
+                    let size_mb = value
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(DEFAULT_HASH_MB)
+                        .clamp(1, MAX_HASH_MB);
+                    search.set_hash(size_mb);
+                }
+                "Clear Hash" => search.clear_hash(),
+                "MultiPV" => {
+                    multipv = value
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(1)
+                        .clamp(1, MAX_MULTIPV);
+                }
+                "SyzygyPath" => {
+                    let path = value.unwrap_or_default();
+                    let tables = if path.is_empty() || path == "<empty>" {
+                        Ok(Tablebases::empty())
+                    } else {
+                        Tablebases::load(&path)
+                    };
+
+                    match tables {
+                        Ok(tables) => {
+                            println!(
+                                "info string Syzygy tables up to {} pieces",
+                                tables.max_pieces()
+                            );
+                            search.set_tablebases(Arc::new(tables));
+                        }
+                        Err(err) => println!("info string Failed to load {}: {}", path, err),
+                    }
+                }
+                "EvalFile" => {
+                    let path = value.unwrap_or_default();
+                    let model = if path.is_empty() || path == "<embedded>" {
+                        Ok(embedded_model())
+                    } else {
+                        NnueModel::load(&path)
+                    };
+
+                    match model {
+                        Ok(model) => {
+                            println!("info string NNUE net: {}", model.arch);
+                            search.set_model(Arc::new(model));
+                        }
+                        Err(err) => println!("info string Failed to load {}: {}", path, err),
+                    }
+                }
+                "OwnBook" => {
+                    own_book = value.is_some_and(|v| v == "true");
+                    search.set_book(book.clone().filter(|_| own_book));
+                }
+                "BookFile" => {
+                    let path = value.unwrap_or_default();
+                    book = if path.is_empty() || path == "<empty>" {
+                        None
+                    } else {
+                        match Book::load(&path) {
+                            Ok(book) => {
+                                println!("info string Book loaded with {} entries", book.len());
+                                Some(Arc::new(book))
+                            }
+                            Err(err) => {
+                                println!("info string Failed to load {}: {}", path, err);
+                                None
+                            }
+                        }
+                    };
+                    search.set_book(book.clone().filter(|_| own_book));
+                }
+                "UCI_Chess960" => {
+                    // FENs are parsed and castling moves are sent as king takes rook
+                    castling_mode = CastlingMode::from_chess960(value.is_some_and(|v| v == "true"));
+                    search.set_castling_mode(castling_mode);
+                }
+                "Ponder" => {} // pondering is controlled by the GUI with `go ponder`
+                _ => {
+                    let param = value.and_then(|v| v.parse::<i32>().ok());
+                    if !param.is_some_and(|value| search.set_param(&name, value)) {
+                        println!("info string Unknown option: {}", name);
+                    }
+                }
+            },
+            UciMessage::UciNewGame => search.new_game(),
             UciMessage::Position {
                 startpos,
                 fen,
@@ -76,7 +282,7 @@ fn main() {
                         .0
                         .parse::<Fen>()
                         .expect("a valid fen")
-                        .into_position(CastlingMode::Standard)
+                        .into_position(castling_mode)
                         .unwrap()
                 };
 
@@ -91,20 +297,18 @@ fn main() {
                 time_control,
                 search_control,
             } => {
-                let best_move = search.go(SearchLimits::from_uci(
-                    time_control,
-                    search_control,
-                    search.pos.get().turn(),
-                ));
-
-                println!(
-                    "bestmove {}",
-                    best_move
-                        .expect("a best move")
-                        .to_uci(CastlingMode::Standard)
-                );
+                let position = search.position();
+                let mut limits = SearchLimits::from_uci(time_control, search_control, &position);
+                limits.ponder |= ponder;
+                limits.multipv = multipv;
+                search.go(limits);
             }
             _ => {}
         }
     }
 }
+
+/// Network embedded in the binary
+fn embedded_model() -> NnueModel {
+    NnueModel::from_memory(include_bytes!("../../models/best.nn")).expect("a valid embedded NNUE")
+}
@@ -0,0 +1,53 @@
+/// Search parameters that can be changed through UCI options (useful for tuning)
+#[derive(Clone)]
+pub struct SearchParams {
+    /// Null move depth reduction (R)
+    /// https://www.chessprogramming.org/Depth_Reduction_R
+    pub null_move_r: i32,
+    /// Depth reduction for late moves (LMR)
+    pub lmr_reduction: i32,
+    /// Number of checks allowed in quiescence search
+    pub qsearch_checks: i32,
+}
+
+/// UCI spin options of the search parameters: (name, default, min, max)
+pub const SEARCH_PARAMS_OPTIONS: [(&str, i32, i32, i32); 3] = [
+    ("NullMoveR", 2, 1, 6),
+    ("LMRReduction", 3, 1, 8),
+    ("QSearchChecks", 3, 0, 16),
+];
+
+impl SearchParams {
+    /// Sets the parameter with the given UCI option name
+    /// Returns false if there is no such parameter
+    pub fn set(&mut self, name: &str, value: i32) -> bool {
+        let Some(&(_, _, min, max)) = SEARCH_PARAMS_OPTIONS.iter().find(|(n, ..)| *n == name)
+        else {
+            return false;
+        };
+        let value = value.clamp(min, max);
+
+        match name {
+            "NullMoveR" => self.null_move_r = value,
+            "LMRReduction" => self.lmr_reduction = value,
+            "QSearchChecks" => self.qsearch_checks = value,
+            _ => unreachable!(),
+        }
+
+        true
+    }
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        let mut params = SearchParams {
+            null_move_r: 0,
+            lmr_reduction: 0,
+            qsearch_checks: 0,
+        };
+        for (name, default, _, _) in SEARCH_PARAMS_OPTIONS {
+            params.set(name, default);
+        }
+        params
+    }
+}
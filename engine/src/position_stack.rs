@@ -1,11 +1,14 @@
 use crate::defs::{HashKey, MAX_PLY};
-use nn::nnue::{accumulator::NnueAccumulator, model::NnueModel};
+use nn::nnue::{
+    accumulator::{NnueAccumulator, RefreshCache},
+    model::NnueModel,
+};
 use shakmaty::{
     uci::UciMove,
     zobrist::{Zobrist64, ZobristHash},
     Chess, Color, EnPassantMode, Move, Position, Role,
 };
-use std::{cell::RefCell, rc::Rc};
+use std::sync::Arc;
 
 pub struct State {
     /// The current position
@@ -19,8 +22,13 @@ pub struct State {
     /// Rule50 counter
     rule50: u32,
 
+    /// Move that led to this position from the previous state, None for null moves (and the root)
+    mov: Option<Move>,
+
     // NNUE accumulator
+    // it is only valid if `nnue_computed` is true, updates are deferred until the position is evaluated
     nnue_accum: NnueAccumulator,
+    nnue_computed: bool,
 }
 
 pub struct PositionStack {
@@ -30,28 +38,25 @@ pub struct PositionStack {
 
     /// Repetition list (previous to stack[0])
     repetitions: Vec<HashKey>,
-}
 
-impl State {
-    fn copy_from(&mut self, other: &State) {
-        self.pos = other.pos.clone();
-        self.hash_key = other.hash_key;
-        self.rule50 = other.rule50;
-        self.nnue_accum.copy_from(&other.nnue_accum);
-    }
+    /// Accumulators to refresh from, for feature sets that require refreshes
+    refresh_cache: RefreshCache,
 }
 
 impl PositionStack {
-    pub fn new(nnue_model: Rc<RefCell<NnueModel>>) -> Self {
+    pub fn new(nnue_model: Arc<NnueModel>) -> Self {
         PositionStack {
             index: 0,
             stack: std::array::from_fn(|_| State {
                 pos: Chess::default(),
                 hash_key: Zobrist64(0),
                 rule50: 0,
+                mov: None,
                 nnue_accum: NnueAccumulator::new(nnue_model.clone()), // Tensors are created here
+                nnue_computed: false,
             }),
             repetitions: Vec::with_capacity(128),
+            refresh_cache: RefreshCache::new(&nnue_model),
         }
     }
 
@@ -87,12 +92,15 @@ impl PositionStack {
         self.stack[0].pos = position.clone();
         self.stack[0].hash_key = position.zobrist_hash(EnPassantMode::Legal);
         self.stack[0].rule50 = rule50;
+        self.stack[0].mov = None;
         self.stack[0].nnue_accum.refresh(&position, Color::White);
         self.stack[0].nnue_accum.refresh(&position, Color::Black);
+        self.stack[0].nnue_computed = true;
     }
 
     /// Makes a move, or a null move if None.
     /// The move is assumed to be legal.
+    /// The NNUE accumulator is not updated until the position is evaluated
     pub fn do_move(&mut self, mov: Option<Move>) {
         // increment stack and copy
         let (prevs, nexts) = self.stack.split_at_mut(self.index + 1);
@@ -100,21 +108,17 @@ impl PositionStack {
         let next_state = &mut nexts[0];
         self.index += 1;
 
-        next_state.copy_from(prev_state);
+        next_state.pos = prev_state.pos.clone();
+        next_state.hash_key = prev_state.hash_key;
+        next_state.rule50 = prev_state.rule50;
+        next_state.mov = mov.clone();
+        next_state.nnue_computed = false;
 
         // increment rule50 counter
         // may be reset by a pawn move or a capture
         next_state.rule50 += 1;
 
         if let Some(mov) = mov {
-            // update the NNUE accumulator (BEFORE making the move!)
-            next_state
-                .nnue_accum
-                .update(&next_state.pos, &mov, Color::White);
-            next_state
-                .nnue_accum
-                .update(&next_state.pos, &mov, Color::Black);
-
             // make a regular move
             next_state.pos.play_unchecked(&mov);
 
@@ -139,9 +143,45 @@ impl PositionStack {
 
     /// Return the evaluation for the current position
     pub fn evaluate(&mut self) -> i32 {
-        let side_to_move = self.get().turn();
+        self.compute_accumulator();
+
+        let state = &self.stack[self.index];
 
-        self.stack[self.index].nnue_accum.forward(side_to_move)
+        state
+            .nnue_accum
+            .forward(state.pos.board(), state.pos.turn())
+    }
+
+    /// Brings the NNUE accumulator of the current position up to date,
+    /// applying the deferred moves starting from the last computed ancestor
+    fn compute_accumulator(&mut self) {
+        // the root is always computed
+        let mut first = self.index;
+        while !self.stack[first].nnue_computed {
+            first -= 1;
+        }
+
+        for i in first + 1..=self.index {
+            let (prevs, nexts) = self.stack.split_at_mut(i);
+            let prev_state = &prevs[i - 1];
+            let state = &mut nexts[0];
+
+            state.nnue_accum.copy_from(&prev_state.nnue_accum);
+
+            if let Some(mov) = &state.mov {
+                // the update is based on the position BEFORE making the move
+                for perspective in [Color::White, Color::Black] {
+                    state.nnue_accum.update_with_cache(
+                        &prev_state.pos,
+                        mov,
+                        perspective,
+                        &mut self.refresh_cache,
+                    );
+                }
+            }
+
+            state.nnue_computed = true;
+        }
     }
 
     /// Returns true if the current position is a draw
@@ -193,3 +233,158 @@ fn should_reset_50_rule(mov: &Move) -> bool {
     // - pawn moves
     mov.is_capture() || mov.role() == Role::Pawn
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode, CastlingSide, Square};
+
+    /// Evaluations with deferred updates must match refreshing the accumulator from scratch
+    #[test]
+    fn test_deferred_updates() {
+        let model =
+            Arc::new(NnueModel::from_memory(include_bytes!("../../models/best.nn")).unwrap());
+        let mut stack = PositionStack::new(model.clone());
+        stack.reset(Chess::default(), vec![]);
+
+        let expected = |stack: &PositionStack| {
+            let pos = stack.get();
+            let mut accum = NnueAccumulator::new(model.clone());
+            accum.refresh(pos, Color::White);
+            accum.refresh(pos, Color::Black);
+            accum.forward(pos.board(), pos.turn())
+        };
+        let play = |stack: &mut PositionStack, uci: &str| {
+            let mov = uci
+                .parse::<UciMove>()
+                .unwrap()
+                .to_move(stack.get())
+                .unwrap();
+            stack.do_move(Some(mov));
+        };
+
+        // several plies without evaluating, including a null move and a capture
+        for uci in ["e2e4", "d7d5"] {
+            play(&mut stack, uci);
+        }
+        stack.do_move(None);
+        play(&mut stack, "d5e4");
+        assert_eq!(stack.evaluate(), expected(&stack));
+
+        // siblings reuse the computed ancestors
+        stack.undo_move();
+        play(&mut stack, "g8f6");
+        play(&mut stack, "b1c3");
+        assert_eq!(stack.evaluate(), expected(&stack));
+        stack.undo_move();
+        assert_eq!(stack.evaluate(), expected(&stack));
+
+        // castling after going back to the root
+        while stack.index > 0 {
+            stack.undo_move();
+        }
+        for uci in ["g1f3", "g8f6", "g2g3", "g7g6", "f1g2", "f8g7", "e1g1"] {
+            play(&mut stack, uci);
+        }
+        assert_eq!(stack.evaluate(), expected(&stack));
+    }
+
+    /// Same as above with a king-bucketed network, where moving the king refreshes from the cache
+    #[test]
+    fn test_refresh_cache() {
+        let (file, _) =
+            nn::nnue::model::test_utils::random_network("hka8", 8 * 64 * 11, 16, &[vec![8, 1]]);
+        let model = Arc::new(NnueModel::from_memory(&file).unwrap());
+        let mut stack = PositionStack::new(model.clone());
+        stack.reset(Chess::default(), vec![]);
+
+        let expected = |stack: &PositionStack| {
+            let pos = stack.get();
+            let mut accum = NnueAccumulator::new(model.clone());
+            accum.refresh(pos, Color::White);
+            accum.refresh(pos, Color::Black);
+            accum.forward(pos.board(), pos.turn())
+        };
+        let play = |stack: &mut PositionStack, uci: &str| {
+            let mov = uci
+                .parse::<UciMove>()
+                .unwrap()
+                .to_move(stack.get())
+                .unwrap();
+            stack.do_move(Some(mov));
+        };
+
+        // the kings cross refresh buckets and the E/D file boundary, evaluating at every ply
+        let line = [
+            "e2e4", "e7e5", "d2d4", "e5d4", "e1e2", "e8e7", "e2d3", "e7d6", "d3d4", "d6c6", "c2c4",
+            "c6b6", "d4e3", "b6c6", "e3e2", "c6d6", "e2d2", "d6e7",
+        ];
+        for uci in line {
+            play(&mut stack, uci);
+            assert_eq!(stack.evaluate(), expected(&stack));
+        }
+
+        // going back across the boundary, the cache now holds the buckets visited above
+        for _ in 0..8 {
+            stack.undo_move();
+            assert_eq!(stack.evaluate(), expected(&stack));
+        }
+
+        // deferred updates over king moves
+        for uci in ["d4c3", "c6d6", "c3b3", "d6e6"] {
+            play(&mut stack, uci);
+        }
+        assert_eq!(stack.evaluate(), expected(&stack));
+    }
+
+    /// Chess960 castling in king to rook notation, where the king only moves one square
+    #[test]
+    fn test_chess960_castling() {
+        let model =
+            Arc::new(NnueModel::from_memory(include_bytes!("../../models/best.nn")).unwrap());
+        let mut stack = PositionStack::new(model.clone());
+
+        let pos: Chess = "rk4r1/pppppppp/8/8/8/8/PPPPPPPP/RK4R1 w GAga - 0 1"
+            .parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Chess960)
+            .unwrap();
+        stack.reset(pos, vec!["b1a1".parse().unwrap()]);
+
+        // queen side castling, not a king move to c1
+        let board = stack.get().board();
+        assert_eq!(board.piece_at(Square::C1), Some(Color::White.king()));
+        assert_eq!(board.piece_at(Square::D1), Some(Color::White.rook()));
+        assert!(!stack
+            .get()
+            .castles()
+            .has(Color::White, CastlingSide::QueenSide));
+
+        let mov = "b8a8"
+            .parse::<UciMove>()
+            .unwrap()
+            .to_move(stack.get())
+            .unwrap();
+        assert!(mov.is_castle());
+        assert_eq!(mov.to_uci(CastlingMode::Chess960).to_string(), "b8a8");
+        assert_eq!(mov.to_uci(CastlingMode::Standard).to_string(), "b8c8");
+
+        stack.do_move(Some(mov));
+        let expected = {
+            let pos = stack.get();
+            let mut accum = NnueAccumulator::new(model.clone());
+            accum.refresh(pos, Color::White);
+            accum.refresh(pos, Color::Black);
+            accum.forward(pos.board(), pos.turn())
+        };
+        assert_eq!(stack.evaluate(), expected);
+        assert_eq!(
+            stack.get().board().piece_at(Square::C8),
+            Some(Color::Black.king())
+        );
+        assert_eq!(
+            stack.hash_key(),
+            stack.get().zobrist_hash::<Zobrist64>(EnPassantMode::Legal)
+        );
+    }
+}
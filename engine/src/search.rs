@@ -1,17 +1,26 @@
 use crate::{
-    defs::{Value, INFINITY, INVALID_MOVE, MAX_PLY},
+    defs::{mate_in, Value, INFINITY, INVALID_MOVE, MATE, MATE_THRESHOLD, MAX_PLY},
     limits::SearchLimits,
+    params::SearchParams,
     position_stack::PositionStack,
     pv_table::PVTable,
-    transposition_table::{TFlag, TranspositionTable},
+    see::see,
+    tablebase::Tablebases,
+    threads::SharedState,
+    transposition_table::TFlag,
 };
 use nn::nnue::model::NnueModel;
 use shakmaty::{uci::UciMove, CastlingMode, Chess, Move, MoveList, Position};
+use std::sync::{atomic::Ordering, Arc};
 use std::time::Instant;
-use std::{cell::RefCell, rc::Rc};
 
-/// Chess search engine
+/// Chess search engine (a single search thread)
 pub struct Search {
+    /// Index of the thread, 0 is the main thread
+    pub thread_id: usize,
+    /// State shared with the other search threads (transposition table, counters, stop flag)
+    pub shared: Arc<SharedState>,
+
     /// Current position
     pub pos: PositionStack,
     /// Current ply
@@ -23,38 +32,68 @@ pub struct Search {
     pub nodes: usize,
     /// Number of evals computed
     pub evals: usize,
+    /// Number of nodes and evals already added to the shared counters
+    reported: (usize, usize),
 
     /// Principal variation table
     pub pv: PVTable,
-    /// Transposition table
-    pub tt: TranspositionTable,
+    /// Best line found in the last completed iteration
+    pub best_line: Option<Vec<Move>>,
+    /// Score of the best line
+    pub best_score: Value,
+    /// Number of consecutive iterations in which the best move did not change
+    pub best_move_stability: u32,
+    /// Lines found in the last completed iteration with their scores, sorted by score (MultiPV)
+    /// The first one is the best line
+    pub lines: Vec<(Vec<Move>, Value)>,
+    /// Root moves that must not be searched, because they are already the start of another line (MultiPV)
+    excluded_root_moves: Vec<Move>,
 
     pub killer_moves: [[Move; 2]; MAX_PLY],
     pub history_moves: [[Value; 8 * 8]; 12],
 
     /// Start search time
     pub start_time: Instant,
+    /// Time from which the time limit is counted (after `ponderhit` when pondering)
+    pub clock_start: Instant,
     /// Whether the search was aborted
     pub aborted: bool,
     /// Limits
     pub limits: SearchLimits,
+    /// Tunable search parameters
+    pub params: SearchParams,
+    /// Endgame tablebases
+    pub tablebases: Arc<Tablebases>,
+    /// How castling moves are printed (`UCI_Chess960`)
+    pub castling_mode: CastlingMode,
 }
 
 impl Search {
-    pub fn new(nnue_model: Rc<RefCell<NnueModel>>) -> Self {
+    pub fn new(nnue_model: Arc<NnueModel>, shared: Arc<SharedState>, thread_id: usize) -> Self {
         let mut search = Search {
-            pos: PositionStack::new(nnue_model.clone()),
+            thread_id,
+            shared,
+            pos: PositionStack::new(nnue_model),
             ply: 0,
             depth_reached: 0,
             nodes: 0,
             evals: 0,
+            reported: (0, 0),
             pv: PVTable::new(),
-            tt: TranspositionTable::new(128), // 128 MB by default
+            best_line: None,
+            best_score: 0,
+            best_move_stability: 0,
+            lines: vec![],
+            excluded_root_moves: vec![],
             killer_moves: std::array::from_fn(|_| [INVALID_MOVE, INVALID_MOVE]),
             history_moves: [[0; 8 * 8]; 12],
             start_time: Instant::now(),
+            clock_start: Instant::now(),
             aborted: false,
             limits: SearchLimits::none(),
+            params: SearchParams::default(),
+            tablebases: Arc::new(Tablebases::empty()),
+            castling_mode: CastlingMode::Standard,
         };
         search.set_position(Chess::default(), vec![]);
         search
@@ -72,23 +111,62 @@ impl Search {
 
     /// Runs the search with the given limits
     /// Returns the best move found
+    ///
+    /// Only the main thread enforces the limits and prints the search info,
+    /// helper threads run until the shared stop flag is set
     pub fn go(&mut self, limits: SearchLimits) -> Option<Move> {
         // reset
         self.ply = 0;
         self.depth_reached = 0;
         self.nodes = 0;
         self.evals = 0;
+        self.reported = (0, 0);
         self.limits = limits;
         self.start_time = Instant::now();
+        self.clock_start = self.start_time;
         self.aborted = false;
-
-        // best line found so far
-        let mut best_line = None;
+        self.best_line = None;
+        self.best_score = 0;
+        self.best_move_stability = 0;
+        self.lines.clear();
+
+        // can't search more lines than root moves
+        let num_root_moves = self
+            .pos
+            .get()
+            .legal_moves()
+            .iter()
+            .filter(|move_| self.search_root_move(move_))
+            .count();
+        let num_lines = self.limits.multipv.min(num_root_moves).max(1);
 
         let max_depth = self.limits.depth.unwrap_or(MAX_PLY as i32 - 1);
 
-        for depth in 1..=max_depth {
-            let score = self.negamax(-INFINITY, INFINITY, depth, false);
+        // odd helper threads start one iteration ahead, so threads are not all searching the same depth
+        let start_depth = 1 + (self.thread_id % 2) as i32;
+
+        for depth in start_depth..=max_depth {
+            let mut lines = Vec::with_capacity(num_lines);
+            self.excluded_root_moves.clear();
+
+            for index in 0..num_lines {
+                // start the window from the score of the same line in the previous iteration
+                let prev_score = self.lines.get(index).map(|(_, score)| *score);
+                let score = self.aspiration_search(depth, index, prev_score);
+
+                if self.aborted {
+                    break;
+                }
+
+                let line = self.pv.get_mainline();
+                // the line is empty if there are no moves to search at the root (mate or stalemate)
+                if let Some(first) = line.first() {
+                    self.excluded_root_moves.push(first.clone());
+                }
+                lines.push((line, score));
+            }
+
+            self.excluded_root_moves.clear();
 
             if self.aborted {
                 // limit reached
@@ -98,30 +176,195 @@ impl Search {
 
             self.depth_reached = depth;
 
+            // lines searched later may be better, since they are searched with the information of the previous ones
+            lines.sort_by_key(|(_, score)| -score);
+
             // save best line for this depth
-            best_line = Some(self.pv.get_mainline());
+            let (best_line, score) = lines[0].clone();
+            self.lines = lines;
+            let prev_best_move = self.best_line.as_ref().and_then(|line| line.first());
 
-            print!(
-                "info depth {} time {} nodes {} evals {} score cp {} pv ",
-                depth,
-                self.start_time.elapsed().as_millis(),
-                self.nodes,
-                self.evals,
-                score
-            );
-            for mv in best_line.as_ref().unwrap() {
-                print!("{} ", mv.to_uci(CastlingMode::Standard));
+            if prev_best_move.is_some() && prev_best_move == best_line.first() {
+                self.best_move_stability += 1;
+            } else {
+                self.best_move_stability = 0;
+            }
+
+            let prev_score = self.best_score;
+            self.best_line = Some(best_line);
+            self.best_score = score;
+
+            if !self.is_main() {
+                continue;
             }
-            print!("\n");
 
-            if score.abs() >= 9950 {
+            self.report_counters();
+            self.print_info();
+
+            if let Some(mate_limit) = self.limits.mate {
+                // `go mate N`: stop once a mate in N (or less) is found
+                if mate_in(score).is_some_and(|moves| moves > 0 && moves <= mate_limit) {
+                    break;
+                }
+            } else if score.abs() >= MATE_THRESHOLD && !self.limits.infinite && !self.limits.ponder
+            {
                 // mate found
                 print!("info string mate found, stopping search\n");
                 break;
             }
+
+            if self.soft_time_exceeded(depth, prev_score, score) {
+                break;
+            }
+        }
+
+        self.report_counters();
+
+        self.best_line.as_ref()?.first().cloned()
+    }
+
+    /// Whether the move should be searched at the root:
+    /// it must be one of the `go searchmoves` (if any) and not the start of another line already (MultiPV)
+    fn search_root_move(&self, move_: &Move) -> bool {
+        self.limits
+            .search_moves
+            .as_ref()
+            .is_none_or(|moves| moves.contains(move_))
+            && !self.excluded_root_moves.contains(move_)
+    }
+
+    /// Whether the search should stop before starting a new iteration, because the soft time limit has been reached
+    /// The limit is scaled depending on how stable the search is:
+    /// - if the best move has not changed for a few iterations, stop earlier
+    /// - if the score drops from the previous iteration, spend more time to find a better move
+    fn soft_time_exceeded(&self, depth: i32, prev_score: Value, score: Value) -> bool {
+        let Some(soft_time) = self.limits.soft_time else {
+            return false;
+        };
+
+        if self.shared.ponder.load(Ordering::Relaxed) {
+            // the clock has not started yet
+            return false;
         }
 
-        best_line.expect("No PV line found").first().cloned()
+        let stability_factor = (1.4 - 0.1 * self.best_move_stability as f32).max(0.5);
+
+        let score_drop = if depth > 1 { prev_score - score } else { 0 };
+        let score_factor = 1.0 + score_drop.clamp(0, 150) as f32 / 150.0;
+
+        self.clock_start.elapsed().as_secs_f32()
+            >= soft_time.as_secs_f32() * stability_factor * score_factor
+    }
+
+    /// Aspiration windows
+    /// https://www.chessprogramming.org/Aspiration_Windows
+    /// --------------------------------
+    /// The iteration starts with a narrow window around the score of the previous one.
+    /// If the score falls outside the window, it is searched again with a wider window.
+    ///
+    /// `index` is the index of the line being searched (MultiPV), bounds are only reported for the first one
+    fn aspiration_search(&mut self, depth: i32, index: usize, prev_score: Option<Value>) -> Value {
+        const MIN_DEPTH: i32 = 4;
+        const INITIAL_DELTA: Value = 25;
+
+        let prev_score = match prev_score {
+            Some(score) if depth >= MIN_DEPTH && score.abs() < MATE_THRESHOLD => score,
+            // scores are not stable enough yet (or a mate has been found)
+            _ => return self.negamax(-INFINITY, INFINITY, depth, false),
+        };
+
+        let mut delta = INITIAL_DELTA;
+        let mut alpha = (prev_score - delta).max(-INFINITY);
+        let mut beta = (prev_score + delta).min(INFINITY);
+
+        loop {
+            let score = self.negamax(alpha, beta, depth, false);
+
+            if self.aborted {
+                return score;
+            }
+
+            // the PV of a failed search is not reliable (the root may not have written any move),
+            // and the one of the previous iteration does not match the score, so bounds are sent without it
+            if score <= alpha {
+                // fail low, the real score is lower
+                if self.is_main() && index == 0 {
+                    self.report_counters();
+                    self.print_info_line(1, depth, &[], score, " upperbound");
+                }
+                alpha = (alpha - delta).max(-INFINITY);
+            } else if score >= beta {
+                // fail high, the real score is higher
+                if self.is_main() && index == 0 {
+                    self.report_counters();
+                    self.print_info_line(1, depth, &[], score, " lowerbound");
+                }
+                beta = (beta + delta).min(INFINITY);
+            } else {
+                return score;
+            }
+
+            delta *= 2;
+        }
+    }
+
+    /// Prints the UCI info lines of the last completed iteration, one per line (MultiPV)
+    /// Nodes and evals are the totals of all threads
+    pub fn print_info(&self) {
+        for (index, (line, score)) in self.lines.iter().enumerate() {
+            self.print_info_line(index + 1, self.depth_reached, line, *score, "");
+        }
+    }
+
+    /// Prints an UCI info line with the given line and score, the pv is omitted if the line is empty
+    /// The bound is empty if the score is exact, otherwise " lowerbound" or " upperbound"
+    fn print_info_line(
+        &self,
+        multipv: usize,
+        depth: i32,
+        line: &[Move],
+        score: Value,
+        bound: &str,
+    ) {
+        let score = match mate_in(score) {
+            Some(moves) => format!("mate {}", moves),
+            None => format!("cp {}", score),
+        };
+
+        print!(
+            "info multipv {} depth {} time {} nodes {} evals {} hashfull {} score {}{}",
+            multipv,
+            depth,
+            self.start_time.elapsed().as_millis(),
+            self.shared.nodes.load(Ordering::Relaxed),
+            self.shared.evals.load(Ordering::Relaxed),
+            self.shared.tt.hashfull(),
+            score,
+            bound
+        );
+        if !line.is_empty() {
+            print!(" pv");
+            for mv in line {
+                print!(" {}", mv.to_uci(self.castling_mode));
+            }
+        }
+        print!("\n");
+    }
+
+    /// Whether this is the main search thread
+    fn is_main(&self) -> bool {
+        self.thread_id == 0
+    }
+
+    /// Adds the nodes and evals visited since the last report to the shared counters
+    fn report_counters(&mut self) {
+        self.shared
+            .nodes
+            .fetch_add(self.nodes - self.reported.0, Ordering::Relaxed);
+        self.shared
+            .evals
+            .fetch_add(self.evals - self.reported.1, Ordering::Relaxed);
+        self.reported = (self.nodes, self.evals);
     }
 
     fn quiescence(&mut self, mut alpha: Value, beta: Value, checks: i32) -> Value {
@@ -173,6 +416,11 @@ impl Search {
         for move_ in moves {
             debug_assert!(move_.is_capture());
 
+            if see(self.pos.get(), &move_) < 0 {
+                // losing capture, it is not going to improve alpha
+                continue;
+            }
+
             self.pos.do_move(Some(move_.clone()));
             self.ply += 1;
             let score = -self.quiescence(
@@ -230,9 +478,10 @@ impl Search {
         let mut pv_move = None;
 
         if !is_pv && self.pos.rule50() < 90 {
-            if let Some(score) = self.tt.read_entry(
+            if let Some(score) = self.shared.tt.read_entry(
                 self.pos.get(),
                 self.pos.hash_key(),
+                self.ply,
                 alpha,
                 beta,
                 depth,
@@ -243,10 +492,19 @@ impl Search {
             }
         }
 
+        // Endgame tablebases
+        // The WDL tables are only accurate right after a capture or pawn move,
+        // since they don't know about the 50-move counter
+        if self.ply > 0 && self.pos.rule50() == 0 {
+            if let Some(score) = self.tablebases.probe_wdl(self.pos.get(), self.ply) {
+                return score;
+            }
+        }
+
         if depth == 0 {
             // escape from recursion
             // run quiescence search
-            return self.quiescence(alpha, beta, 3); // allow up to three checks
+            return self.quiescence(alpha, beta, self.params.qsearch_checks); // allow up to N checks
         }
 
         let in_check = self.pos.get().is_check();
@@ -270,13 +528,13 @@ impl Search {
         //
         // R: depth reduction value
         // https://www.chessprogramming.org/Depth_Reduction_R
-        const R: i32 = 2;
-        if allow_null && self.ply > 0 && depth >= R + 1 && !in_check {
+        let r = self.params.null_move_r;
+        if allow_null && self.ply > 0 && depth >= r + 1 && !in_check {
             // make a null move
             // (forfeit the move and let the opponent play)
             self.pos.do_move(None);
             self.ply += 1;
-            let score = -self.negamax(-beta, -beta + 1, depth - R - 1, false);
+            let score = -self.negamax(-beta, -beta + 1, depth - r - 1, false);
             self.ply -= 1;
             self.pos.undo_move();
 
@@ -291,6 +549,14 @@ impl Search {
         // generate legal moves
         let mut moves = self.pos.get().legal_moves();
 
+        if self.ply == 0 {
+            moves.retain(|move_| self.search_root_move(move_));
+        }
+
+        // results at the root with excluded moves are not valid for the position
+        let store_tt = self.ply > 0
+            || (self.excluded_root_moves.is_empty() && self.limits.search_moves.is_none());
+
         // sort moves
         self.sort_moves(&mut moves, pv_move);
 
@@ -313,9 +579,9 @@ impl Search {
                 score = -self.negamax(-beta, -alpha, depth - 1, true);
             } else {
                 // Late move reductions (LMR)
-                const LMR_REDUCTION: i32 = 3;
+                let lmr_reduction = self.params.lmr_reduction;
                 if moves_searched >= 2 &&
-                    depth >= LMR_REDUCTION &&
+                    depth >= lmr_reduction &&
                     !in_check &&
                     // no capture
                     !move_.is_capture() &&
@@ -323,7 +589,7 @@ impl Search {
                     !move_.is_promotion()
                 {
                     // search with reduced depth
-                    score = -self.negamax(-(alpha + 1), -alpha, depth - LMR_REDUCTION, allow_null);
+                    score = -self.negamax(-(alpha + 1), -alpha, depth - lmr_reduction, allow_null);
                 } else {
                     // make sure a full search is done
                     score = alpha + 1;
@@ -360,8 +626,16 @@ impl Search {
                 }
 
                 // store TT entry
-                self.tt
-                    .write_entry(self.pos.hash_key(), move_, beta, depth, TFlag::Beta);
+                if store_tt {
+                    self.shared.tt.write_entry(
+                        self.pos.hash_key(),
+                        self.ply,
+                        move_,
+                        beta,
+                        depth,
+                        TFlag::Beta,
+                    );
+                }
 
                 // fails high
                 return beta;
@@ -391,20 +665,23 @@ impl Search {
         if best_move.is_none() {
             if in_check {
                 // checkmate
-                return -10_000 + self.ply as i32;
+                return -MATE + self.ply as i32;
             } else {
                 // stalemate (draw)
                 return 0;
             }
         }
 
-        self.tt.write_entry(
-            self.pos.hash_key(),
-            best_move.unwrap(),
-            alpha,
-            depth,
-            tt_alpha_flag,
-        );
+        if store_tt {
+            self.shared.tt.write_entry(
+                self.pos.hash_key(),
+                self.ply,
+                best_move.unwrap(),
+                alpha,
+                depth,
+                tt_alpha_flag,
+            );
+        }
 
         // node fails low
         alpha
@@ -438,7 +715,13 @@ impl Search {
             }
 
             if move_.is_capture() {
-                score += mvvlva(move_) + 10_000;
+                score += mvvlva(move_);
+                score += if see(self.pos.get(), move_) >= 0 {
+                    10_000
+                } else {
+                    // losing capture, try it after the quiet moves
+                    -10_000
+                };
             } else {
                 // move is quiet
                 score += if self.killer_moves[self.ply][0] == *move_ {
@@ -457,20 +740,37 @@ impl Search {
         });
     }
 
+    /// Checks the limits and the shared stop flag (set by `stop` or when the limits are reached)
     fn checkup(&mut self) {
-        if self.nodes & 2047 == 0 && self.depth_reached > 0 {
+        if self.nodes & 2047 == 0 {
+            self.report_counters();
+
+            if self.shared.ponder.load(Ordering::Relaxed) {
+                // the clock starts after ponderhit
+                self.clock_start = Instant::now();
+            }
+
             // make sure we are not exceeding the limits
-            if let Some(time_limit) = self.limits.time {
-                if self.start_time.elapsed() >= time_limit {
-                    self.aborted = true;
+            // (only the main thread checks them, and only after completing the first iteration)
+            if self.is_main() && self.depth_reached > 0 {
+                if let Some(time_limit) = self.limits.time {
+                    if self.clock_start.elapsed() >= time_limit {
+                        self.shared.stop.store(true, Ordering::Relaxed);
+                    }
                 }
-            }
 
-            if let Some(nodes_limit) = self.limits.nodes {
-                if self.nodes >= nodes_limit {
-                    self.aborted = true;
+                if let Some(nodes_limit) = self.limits.nodes {
+                    if self.shared.nodes.load(Ordering::Relaxed) >= nodes_limit {
+                        self.shared.stop.store(true, Ordering::Relaxed);
+                    }
                 }
             }
+
+            if self.shared.stop.load(Ordering::Relaxed)
+                && (self.depth_reached > 0 || !self.is_main())
+            {
+                self.aborted = true;
+            }
         }
     }
 }
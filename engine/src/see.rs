@@ -0,0 +1,167 @@
+use crate::defs::Value;
+use shakmaty::{Bitboard, Chess, Move, Position, Role, Square};
+
+/// Piece values used in the exchanges
+const SEE_VALUES: [Value; 6] = [
+    100,    // Pawn
+    300,    // Knight
+    300,    // Bishop
+    500,    // Rook
+    900,    // Queen
+    20_000, // King
+];
+
+fn value(role: Role) -> Value {
+    SEE_VALUES[role as usize - 1]
+}
+
+/// Static Exchange Evaluation
+/// https://www.chessprogramming.org/Static_Exchange_Evaluation
+/// --------------------------------
+/// Material balance after all the captures on the destination square of the move,
+/// each side capturing with its least valuable piece and allowed to stop when it is not favorable.
+/// Pins and checks are not taken into account.
+pub fn see(pos: &Chess, mov: &Move) -> Value {
+    let (from, to) = match mov {
+        Move::Castle { .. } | Move::Put { .. } => return 0,
+        _ => (mov.from().unwrap(), mov.to()),
+    };
+
+    let board = pos.board();
+    let mut occupied = board.occupied() ^ Bitboard::from(from);
+
+    // gain[d]: material won by the side that made the capture at depth d, if the exchange stopped there
+    let mut gain = [0; 32];
+    let mut d = 0;
+
+    gain[0] = mov.capture().map_or(0, value);
+    if let Some(promotion) = mov.promotion() {
+        gain[0] += value(promotion) - value(Role::Pawn);
+    }
+    if let Move::EnPassant { .. } = mov {
+        // the captured pawn is not on the destination square
+        occupied ^= Bitboard::from(Square::from_coords(to.file(), from.rank()));
+    }
+
+    // value of the piece standing on the square, the next one to be captured
+    let mut on_square = value(mov.promotion().unwrap_or(mov.role()));
+    let mut color = !pos.turn();
+
+    loop {
+        // attackers are recomputed with the new occupancy, so x-rays are discovered
+        let attackers = board.attacks_to(to, color, occupied) & occupied;
+        if attackers.is_empty() {
+            break;
+        }
+
+        // least valuable attacker
+        let (sq, role) = Role::ALL
+            .iter()
+            .find_map(|&role| {
+                (attackers & board.by_role(role))
+                    .first()
+                    .map(|sq| (sq, role))
+            })
+            .unwrap();
+
+        d += 1;
+        gain[d] = on_square - gain[d - 1];
+
+        if (-gain[d - 1]).max(gain[d]) < 0 {
+            // even in the best case the capture is not favorable, so it is not made
+            d -= 1;
+            break;
+        }
+
+        occupied ^= Bitboard::from(sq);
+        on_square = value(role);
+        color = !color;
+
+        if d == gain.len() - 1 {
+            break;
+        }
+    }
+
+    // each side picks between stopping and continuing the exchange
+    while d > 0 {
+        gain[d - 1] = -(-gain[d - 1]).max(gain[d]);
+        d -= 1;
+    }
+
+    gain[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, uci::UciMove, CastlingMode};
+
+    fn check(fen: &str, uci: &str, expected: Value) {
+        let pos: Chess = fen
+            .parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap();
+        let mov = uci.parse::<UciMove>().unwrap().to_move(&pos).unwrap();
+
+        assert_eq!(see(&pos, &mov), expected, "{} {}", fen, uci);
+    }
+
+    #[test]
+    fn test_undefended_capture() {
+        check(
+            "1k1r4/1pp4p/p7/4p3/8/P5P1/1PP4P/2K1R3 w - - 0 1",
+            "e1e5",
+            100,
+        );
+    }
+
+    #[test]
+    fn test_xrays() {
+        // the queens behind the rook and bishop join the exchange
+        check(
+            "1k1r3q/1ppn3p/p4b2/4p3/8/P2N2P1/1PP1R1BP/2K1Q3 w - - 0 1",
+            "d3e5",
+            100 - 300,
+        );
+        check("3r3k/3r4/8/3p4/8/8/3R4/3RK3 w - - 0 1", "d2d5", 100 - 500);
+    }
+
+    #[test]
+    fn test_equal_trades() {
+        check(
+            "4R3/2r3p1/5bk1/1p1r3p/p2PR1P1/P1BK1P2/1P6/8 b - - 0 1",
+            "h5g4",
+            0,
+        );
+        check(
+            "4R3/2r3p1/5bk1/1p1r1p1p/p2PR1P1/P1BK1P2/1P6/8 b - - 0 1",
+            "h5g4",
+            0,
+        );
+    }
+
+    #[test]
+    fn test_losing_capture() {
+        check("4k3/8/2p5/3p4/8/8/8/3QK3 w - - 0 1", "d1d5", 100 - 900);
+    }
+
+    #[test]
+    fn test_quiet_moves() {
+        check("4k3/8/8/2p5/8/8/2N5/4K3 w - - 0 1", "c2d4", -300);
+        check("4k3/8/8/2p5/8/8/2N5/4K3 w - - 0 1", "c2e3", 0);
+    }
+
+    #[test]
+    fn test_en_passant() {
+        check("8/8/8/8/3Pp3/8/8/4K2k b - d3 0 1", "e4d3", 100);
+        check("8/8/8/8/3Pp3/8/4K3/7k b - d3 0 1", "e4d3", 0);
+    }
+
+    #[test]
+    fn test_promotions() {
+        check("7k/3P4/8/8/8/8/8/K7 w - - 0 1", "d7d8q", 800);
+        check("2r4k/3P4/8/8/8/8/8/K7 w - - 0 1", "d7d8q", -100);
+        check("2r4k/3P4/8/8/8/8/8/K7 w - - 0 1", "d7c8q", 1300);
+    }
+}
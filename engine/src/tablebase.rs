@@ -0,0 +1,171 @@
+use crate::defs::{Value, MATE_THRESHOLD, MAX_PLY};
+use shakmaty::{Chess, Move, Position};
+use shakmaty_syzygy::{Tablebase, Wdl};
+use std::io;
+
+/// Score of a tablebase win at the root, a win found at ply N is scored as `TB_WIN - N`
+/// It is below the mate scores, so a real mate is always preferred
+pub const TB_WIN: Value = MATE_THRESHOLD - MAX_PLY as Value - 1;
+
+/// Syzygy endgame tablebases
+/// https://www.chessprogramming.org/Syzygy_Bases
+pub struct Tablebases {
+    tables: Tablebase<Chess>,
+}
+
+impl Tablebases {
+    /// No tables loaded, probes always miss
+    pub fn empty() -> Self {
+        Tablebases {
+            tables: Tablebase::new(),
+        }
+    }
+
+    /// Loads the tables in the given directories, separated by `:` (`;` on Windows) like other engines
+    pub fn load(path: &str) -> io::Result<Self> {
+        let separator = if cfg!(windows) { ';' } else { ':' };
+        let mut tables = Tablebase::new();
+
+        for dir in path.split(separator).filter(|dir| !dir.is_empty()) {
+            tables.add_directory(dir)?;
+        }
+
+        Ok(Tablebases { tables })
+    }
+
+    /// Maximum number of pieces of the loaded tables (zero if there are no tables)
+    pub fn max_pieces(&self) -> usize {
+        self.tables.max_pieces()
+    }
+
+    /// Whether the position can be found in the tables
+    fn can_probe(&self, pos: &Chess) -> bool {
+        pos.board().occupied().count() <= self.max_pieces() && !pos.castles().any()
+    }
+
+    /// Probes the WDL tables, returns the score of the position for the side to move
+    /// Only valid right after a zeroing move (capture or pawn move), since the tables don't know about the 50-move counter
+    pub fn probe_wdl(&self, pos: &Chess, ply: usize) -> Option<Value> {
+        if !self.can_probe(pos) {
+            return None;
+        }
+
+        let wdl = self.tables.probe_wdl_after_zeroing(pos).ok()?;
+        Some(wdl_to_score(wdl, ply))
+    }
+
+    /// Probes the DTZ tables at the root, returns the move that converts the position
+    /// (or holds it, if it is not winning) taking the 50-move rule into account, and its score
+    pub fn probe_root(&self, pos: &Chess) -> Option<(Move, Value)> {
+        if !self.can_probe(pos) {
+            return None;
+        }
+
+        let dtz = self.tables.probe_dtz(pos).ok()?.ignore_rounding().0;
+        let (best_move, _) = self.tables.best_move(pos).ok()??;
+
+        // wins and losses that can't be completed before the 50-move rule are draws
+        let wdl = if dtz.abs() + pos.halfmoves() as i32 > 100 {
+            Wdl::Draw
+        } else if dtz > 0 {
+            Wdl::Win
+        } else if dtz < 0 {
+            Wdl::Loss
+        } else {
+            Wdl::Draw
+        };
+
+        Some((best_move, wdl_to_score(wdl, 0)))
+    }
+}
+
+/// Converts a WDL result into a score, cursed wins and blessed losses are draws under the 50-move rule
+fn wdl_to_score(wdl: Wdl, ply: usize) -> Value {
+    match wdl {
+        Wdl::Win => TB_WIN - ply as Value,
+        Wdl::Loss => -TB_WIN + ply as Value,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode};
+
+    /// 3-4 man tables (KQvK, KRvK, KNvK, KPvK), fetched with `syzygy/download.sh`.
+    /// They are not committed, the tests that need them are skipped if they are missing
+    const TABLES_PATH: &str = "syzygy";
+
+    /// Loads the test tables, None if they have not been fetched (the tests are skipped then)
+    fn test_tables() -> Option<Tablebases> {
+        match Tablebases::load(TABLES_PATH) {
+            Ok(tb) if tb.max_pieces() > 0 => Some(tb),
+            _ => {
+                eprintln!("no tables in {}, run syzygy/download.sh", TABLES_PATH);
+                None
+            }
+        }
+    }
+
+    fn position(fen: &str) -> Chess {
+        fen.parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_empty() {
+        let tb = Tablebases::empty();
+        let pos = position("8/8/8/8/8/8/2k5/K6Q w - - 0 1");
+
+        assert_eq!(tb.max_pieces(), 0);
+        assert_eq!(tb.probe_wdl(&pos, 0), None);
+        assert!(tb.probe_root(&pos).is_none());
+    }
+
+    #[test]
+    fn test_probe_wdl() {
+        let Some(tb) = test_tables() else {
+            return;
+        };
+
+        // KQvK is a win for the side with the queen
+        assert_eq!(
+            tb.probe_wdl(&position("8/8/8/8/8/8/2k5/K6Q w - - 0 1"), 3),
+            Some(TB_WIN - 3)
+        );
+        assert_eq!(
+            tb.probe_wdl(&position("8/8/8/8/8/8/2k5/K6Q b - - 0 1"), 3),
+            Some(-TB_WIN + 3)
+        );
+        // KPvK with the pawn about to promote and the king far away
+        assert_eq!(
+            tb.probe_wdl(&position("8/4P3/8/8/8/8/k7/4K3 w - - 0 1"), 0),
+            Some(TB_WIN)
+        );
+        // KNvK is a draw
+        assert_eq!(
+            tb.probe_wdl(&position("8/8/8/8/8/8/2k5/K6N w - - 0 1"), 0),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_probe_root() {
+        let Some(tb) = test_tables() else {
+            return;
+        };
+
+        // the rook is attacked and must be saved, Rd1 would hang it
+        let pos = position("8/8/8/8/8/8/2k5/K1R5 w - - 0 1");
+        let (best_move, score) = tb.probe_root(&pos).unwrap();
+        assert_eq!(score, TB_WIN);
+        assert_ne!(best_move.to().to_string(), "d1");
+
+        // a win that takes too long for the 50-move rule is a draw
+        let pos = position("8/8/8/8/8/8/2k5/K1R5 w - - 99 1");
+        assert_eq!(tb.probe_root(&pos).unwrap().1, 0);
+    }
+}
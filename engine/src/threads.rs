@@ -0,0 +1,350 @@
+use crate::{
+    book::Book, limits::SearchLimits, params::SearchParams, search::Search, tablebase::Tablebases,
+    transposition_table::TranspositionTable,
+};
+use nn::nnue::model::NnueModel;
+use shakmaty::{uci::UciMove, CastlingMode, Chess, Move};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Default size of the transposition table in MB
+pub const DEFAULT_HASH_MB: usize = 128;
+
+/// State shared between all the search threads
+pub struct SharedState {
+    /// Transposition table
+    pub tt: TranspositionTable,
+    /// Whether the search must stop
+    pub stop: AtomicBool,
+    /// Whether the search is pondering (waiting for `ponderhit`)
+    pub ponder: AtomicBool,
+    /// Number of nodes searched by all threads
+    pub nodes: AtomicUsize,
+    /// Number of evals computed by all threads
+    pub evals: AtomicUsize,
+}
+
+impl SharedState {
+    pub fn new(tt_size_mb: usize) -> Self {
+        SharedState {
+            tt: TranspositionTable::new(tt_size_mb),
+            stop: AtomicBool::new(false),
+            ponder: AtomicBool::new(false),
+            nodes: AtomicUsize::new(0),
+            evals: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Lazy SMP
+/// https://www.chessprogramming.org/Lazy_SMP
+/// --------------------------------
+/// All threads search the same position at the same time, sharing the transposition table.
+/// Helper threads fill the table with results that the main thread can reuse, and their
+/// results are taken into account when choosing the best move.
+///
+/// The searches run in a background thread, so the UCI loop can keep reading
+/// commands (`stop`, `isready`, `quit`) while searching.
+pub struct ThreadPool {
+    nnue_model: Arc<NnueModel>,
+    shared: Arc<SharedState>,
+
+    /// One search per thread, the first one is the main thread
+    /// Empty while a search is running (they are owned by the worker thread)
+    searches: Vec<Search>,
+    /// Background thread running the current search, it gives the searches back when joined
+    worker: Option<JoinHandle<Vec<Search>>>,
+
+    /// Last position set, so new threads can be initialized with it
+    position: Chess,
+    moves: Vec<UciMove>,
+    /// Search parameters of all threads
+    params: SearchParams,
+    /// Endgame tablebases
+    tablebases: Arc<Tablebases>,
+    /// Opening book, only if the engine should use its own book
+    book: Option<Arc<Book>>,
+    /// How castling moves are printed (`UCI_Chess960`)
+    castling_mode: CastlingMode,
+}
+
+impl ThreadPool {
+    pub fn new(nnue_model: Arc<NnueModel>, num_threads: usize) -> Self {
+        let mut pool = ThreadPool {
+            nnue_model,
+            shared: Arc::new(SharedState::new(DEFAULT_HASH_MB)),
+            searches: Vec::new(),
+            worker: None,
+            position: Chess::default(),
+            moves: vec![],
+            params: SearchParams::default(),
+            tablebases: Arc::new(Tablebases::empty()),
+            book: None,
+            castling_mode: CastlingMode::Standard,
+        };
+        pool.set_threads(num_threads);
+        pool
+    }
+
+    /// Changes the number of search threads (at least one)
+    pub fn set_threads(&mut self, num_threads: usize) {
+        self.wait();
+
+        let num_threads = num_threads.max(1);
+
+        self.searches.truncate(num_threads);
+
+        while self.searches.len() < num_threads {
+            let mut search = Search::new(
+                self.nnue_model.clone(),
+                self.shared.clone(),
+                self.searches.len(),
+            );
+            search.set_position(self.position.clone(), self.moves.clone());
+            search.params = self.params.clone();
+            search.tablebases = self.tablebases.clone();
+            search.castling_mode = self.castling_mode;
+            self.searches.push(search);
+        }
+    }
+
+    /// Resizes the transposition table, its contents are lost
+    pub fn set_hash(&mut self, size_mb: usize) {
+        self.wait();
+
+        self.shared = Arc::new(SharedState::new(size_mb));
+        for search in self.searches.iter_mut() {
+            search.shared = self.shared.clone();
+        }
+    }
+
+    /// Clears the transposition table
+    pub fn clear_hash(&mut self) {
+        self.wait();
+        self.shared.tt.clear();
+    }
+
+    /// Replaces the network used for evaluation
+    pub fn set_model(&mut self, nnue_model: Arc<NnueModel>) {
+        self.wait();
+
+        // the accumulators depend on the model, so the searches are rebuilt
+        let num_threads = self.searches.len();
+        self.nnue_model = nnue_model;
+        self.searches.clear();
+        self.set_threads(num_threads);
+    }
+
+    /// Replaces the endgame tablebases
+    pub fn set_tablebases(&mut self, tablebases: Arc<Tablebases>) {
+        self.wait();
+
+        for search in self.searches.iter_mut() {
+            search.tablebases = tablebases.clone();
+        }
+        self.tablebases = tablebases;
+    }
+
+    /// Sets the opening book to play from, or None to always search
+    pub fn set_book(&mut self, book: Option<Arc<Book>>) {
+        self.wait();
+        self.book = book;
+    }
+
+    /// Sets how castling moves are printed, king to rook in Chess960
+    pub fn set_castling_mode(&mut self, castling_mode: CastlingMode) {
+        self.wait();
+
+        for search in self.searches.iter_mut() {
+            search.castling_mode = castling_mode;
+        }
+        self.castling_mode = castling_mode;
+    }
+
+    /// Sets a search parameter by its UCI option name
+    /// Returns false if there is no such parameter
+    pub fn set_param(&mut self, name: &str, value: i32) -> bool {
+        self.wait();
+
+        if !self.params.set(name, value) {
+            return false;
+        }
+        for search in self.searches.iter_mut() {
+            search.params = self.params.clone();
+        }
+        true
+    }
+
+    /// Resets everything learned from previous searches (transposition table, killer/history moves)
+    /// The options are kept. A running search is stopped, since an infinite or ponder search would never end
+    pub fn new_game(&mut self) {
+        self.stop();
+
+        // rebuild the searches completely,
+        // this way I'm sure I'm not leaking anything from the previous game
+        let num_threads = self.searches.len();
+        self.shared.tt.clear();
+        self.searches.clear();
+        self.set_threads(num_threads);
+    }
+
+    /// Set the position to search from in all threads
+    /// A running search is stopped (its bestmove is still sent), since an infinite or ponder search would never end
+    pub fn set_position(&mut self, position: Chess, moves: Vec<UciMove>) {
+        self.stop();
+
+        for search in self.searches.iter_mut() {
+            search.set_position(position.clone(), moves.clone());
+        }
+
+        self.position = position;
+        self.moves = moves;
+    }
+
+    /// Current position to search from
+    pub fn position(&mut self) -> Chess {
+        self.wait();
+        self.searches[0].pos.get().clone()
+    }
+
+    /// Starts searching in the background with the given limits
+    /// When the search is over, the best move (and the expected reply) is sent as `bestmove`
+    pub fn go(&mut self, limits: SearchLimits) {
+        self.wait();
+
+        // reset before spawning, so a `stop` or `ponderhit` right after `go` is not lost
+        self.shared.stop.store(false, Ordering::Relaxed);
+        self.shared.ponder.store(limits.ponder, Ordering::Relaxed);
+        self.shared.nodes.store(0, Ordering::Relaxed);
+        self.shared.evals.store(0, Ordering::Relaxed);
+        self.shared.tt.new_search();
+
+        let shared = self.shared.clone();
+        let castling_mode = self.castling_mode;
+        // the book doesn't know about `go searchmoves`
+        let book = self.book.clone().filter(|_| limits.search_moves.is_none());
+        let mut searches = std::mem::take(&mut self.searches);
+
+        self.worker = Some(thread::spawn(move || {
+            let best_line = match book.and_then(|book| book.pick_move(searches[0].pos.get())) {
+                Some(book_move) => {
+                    println!("info string Book move");
+                    Self::wait_for_stop(&shared, &limits);
+                    vec![book_move]
+                }
+                None => Self::search(&mut searches, &shared, limits),
+            };
+
+            match (best_line.first(), best_line.get(1)) {
+                (Some(best_move), Some(ponder_move)) => println!(
+                    "bestmove {} ponder {}",
+                    best_move.to_uci(castling_mode),
+                    ponder_move.to_uci(castling_mode)
+                ),
+                (Some(best_move), None) => println!("bestmove {}", best_move.to_uci(castling_mode)),
+                // no legal moves at the root, the GUI still expects an answer
+                (None, _) => println!("bestmove 0000"),
+            }
+
+            searches
+        }));
+    }
+
+    /// The opponent played the expected move, the ponder search becomes a normal search
+    pub fn ponderhit(&mut self) {
+        self.shared.ponder.store(false, Ordering::Relaxed);
+    }
+
+    /// Aborts the current search (if any) and waits for it to finish
+    pub fn stop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.wait();
+    }
+
+    /// Waits for the current search (if any) to finish
+    pub fn wait(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.searches = worker.join().expect("search thread panicked");
+        }
+    }
+
+    /// Runs the search in all threads with the given limits
+    /// Returns the best line found, empty if there are no moves to play
+    fn search(searches: &mut [Search], shared: &SharedState, limits: SearchLimits) -> Vec<Move> {
+        let (main, helpers) = searches.split_first_mut().unwrap();
+
+        // `go searchmoves` with only illegal moves: there is nothing to search
+        if matches!(&limits.search_moves, Some(moves) if moves.is_empty()) {
+            println!("info string No legal moves in searchmoves");
+            Self::wait_for_stop(shared, &limits);
+            return vec![];
+        }
+
+        // tablebase hit at the root: play the move that converts (or holds) the position, no need to search
+        // (unless the root moves are restricted)
+        let tb_hit = if limits.search_moves.is_none() {
+            main.tablebases.probe_root(main.pos.get())
+        } else {
+            None
+        };
+        if let Some((best_move, score)) = tb_hit {
+            println!(
+                "info depth 1 tbhits 1 score cp {} pv {}",
+                score,
+                best_move.to_uci(main.castling_mode)
+            );
+            Self::wait_for_stop(shared, &limits);
+            return vec![best_move];
+        }
+
+        thread::scope(|scope| {
+            for helper in helpers.iter_mut() {
+                let limits = limits.clone();
+                scope.spawn(move || helper.go(limits));
+            }
+
+            main.go(limits.clone());
+            Self::wait_for_stop(shared, &limits);
+
+            // the main thread decides when the search is over
+            shared.stop.store(true, Ordering::Relaxed);
+        });
+
+        // pick the thread that completed the deepest iteration, then the best score
+        let Some(best) = searches
+            .iter()
+            .filter(|search| search.best_line.is_some())
+            .max_by_key(|search| (search.depth_reached, search.best_score))
+        else {
+            return vec![];
+        };
+
+        if best.thread_id != 0 {
+            // let the GUI know where the best move comes from
+            best.print_info();
+        }
+
+        best.best_line.clone().unwrap()
+    }
+
+    /// In infinite mode the best move can't be sent until `stop` is received,
+    /// and while pondering until `ponderhit` or `stop` is received
+    fn wait_for_stop(shared: &SharedState, limits: &SearchLimits) {
+        while limits.infinite || shared.ponder.load(Ordering::Relaxed) {
+            if shared.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
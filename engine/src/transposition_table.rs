@@ -1,96 +1,512 @@
-use crate::defs::HashKey;
-use shakmaty::{Chess, Move, Position};
+use crate::defs::{HashKey, Value, MATE_THRESHOLD};
+use shakmaty::{uci::UciMove, CastlingMode, Chess, Move, Role, Square};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 
-#[derive(Clone)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum TFlag {
-    Alpha,
-    Beta,
-    Exact,
+    Alpha = 0,
+    Beta = 1,
+    Exact = 2,
 }
 
+/// Number of entries in a bucket
+const BUCKET_SIZE: usize = 4;
+
+/// Number of distinct generations, it must fit in the 6 bits of the entry
+const GENERATIONS: u8 = 64;
+
+/// Decoded transposition table entry
 #[derive(Clone)]
 pub struct TEntry {
-    pub key: HashKey,
-    pub flag: TFlag,
+    /// Lower 16 bits of the hash key, to verify the entry belongs to the position
+    pub key: u16,
+    /// Packed move, see `pack_move`
+    pub move_: u16,
+    pub score: i16,
     pub depth: i8,
-    pub score: i32,
-    pub move_: Option<Move>,
+    pub flag: TFlag,
+    /// Search in which the entry was written
+    pub generation: u8,
+}
+
+impl TEntry {
+    /// Packs the entry into 64 bits
+    /// key (16) | move (16) | score (16) | depth (8) | flag (2) | generation (6)
+    fn pack(&self) -> u64 {
+        (self.key as u64)
+            | (self.move_ as u64) << 16
+            | (self.score as u16 as u64) << 32
+            | (self.depth as u8 as u64) << 48
+            | (self.flag as u64) << 56
+            | (self.generation as u64) << 58
+    }
+
+    /// Unpacks an entry packed with `TEntry::pack`
+    fn unpack(data: u64) -> Self {
+        TEntry {
+            key: data as u16,
+            move_: (data >> 16) as u16,
+            score: (data >> 32) as u16 as i16,
+            depth: (data >> 48) as u8 as i8,
+            flag: match (data >> 56) as u8 & 0b11 {
+                0 => TFlag::Alpha,
+                1 => TFlag::Beta,
+                _ => TFlag::Exact,
+            },
+            generation: (data >> 58) as u8,
+        }
+    }
+
+    /// How many searches ago the entry was written
+    fn age(&self, generation: u8) -> u8 {
+        generation.wrapping_sub(self.generation) % GENERATIONS
+    }
+}
+
+/// A group of entries that share the same index, they fit in half a cache line.
+/// Each entry is a single atomic word, so threads writing at the same time can't produce a torn entry.
+#[repr(align(32))]
+struct TBucket {
+    entries: [AtomicU64; BUCKET_SIZE],
 }
 
 /// Transposition table
+/// It is lock-free, so it can be shared between search threads
 pub struct TranspositionTable {
-    entries: Vec<TEntry>,
+    buckets: Vec<TBucket>,
+    /// Current search generation, used to age out entries from previous searches
+    generation: AtomicU8,
 }
 
 impl TranspositionTable {
     pub fn new(size_mb: usize) -> Self {
         TranspositionTable {
-            entries: vec![
-                TEntry {
-                    key: HashKey::default(),
-                    flag: TFlag::Exact,
-                    depth: -1,
-                    score: 0,
-                    move_: None,
-                };
-                size_mb * 1024 * 1024 / std::mem::size_of::<TEntry>()
-            ],
+            buckets: (0..(size_mb * 1024 * 1024 / std::mem::size_of::<TBucket>()).max(1))
+                .map(|_| TBucket {
+                    entries: std::array::from_fn(|_| AtomicU64::new(0)),
+                })
+                .collect(),
+            generation: AtomicU8::new(0),
         }
     }
 
-    pub fn write_entry(&mut self, key: HashKey, move_: Move, score: i32, depth: i32, flag: TFlag) {
-        let len = self.entries.len();
-        let entry = &mut self.entries[key.0 as usize % len];
+    /// Removes all the entries
+    pub fn clear(&self) {
+        for bucket in self.buckets.iter() {
+            for entry in bucket.entries.iter() {
+                entry.store(0, Ordering::Relaxed);
+            }
+        }
+        self.generation.store(0, Ordering::Relaxed);
+    }
 
-        // TODO: check for depth and key, if it is worth to replace the entry
+    /// Must be called before starting a new search, so entries from previous searches are replaced first
+    pub fn new_search(&self) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.generation
+            .store((generation + 1) % GENERATIONS, Ordering::Relaxed);
+    }
 
-        *entry = TEntry {
-            key,
-            flag,
+    /// Approximate per mille of the table used by the current search
+    pub fn hashfull(&self) -> usize {
+        let generation = self.generation.load(Ordering::Relaxed);
+
+        // sample the first entries
+        self.buckets
+            .iter()
+            .take(1000 / BUCKET_SIZE)
+            .flat_map(|bucket| bucket.entries.iter())
+            .map(|entry| entry.load(Ordering::Relaxed))
+            .filter(|&data| data != 0 && TEntry::unpack(data).generation == generation)
+            .count()
+            * 1000
+            / (self.buckets.len().min(1000 / BUCKET_SIZE) * BUCKET_SIZE)
+    }
+
+    /// Bucket for the given key
+    /// The upper bits of the key select the bucket and the lower bits are stored in the entry
+    fn bucket(&self, key: HashKey) -> &TBucket {
+        let index = (key.0 as u128 * self.buckets.len() as u128) >> 64;
+        &self.buckets[index as usize]
+    }
+
+    pub fn write_entry(
+        &self,
+        key: HashKey,
+        ply: usize,
+        move_: Move,
+        score: i32,
+        depth: i32,
+        flag: TFlag,
+    ) {
+        let bucket = self.bucket(key);
+        let generation = self.generation.load(Ordering::Relaxed);
+
+        let new_entry = TEntry {
+            key: key.0 as u16,
+            move_: pack_move(&move_),
+            // bounds can be as large as INFINITY, they don't fit in 16 bits but are still valid bounds clamped
+            score: score_to_tt(score, ply).clamp(-(i16::MAX as Value), i16::MAX as Value) as i16,
             depth: depth as i8,
-            score,
-            move_: Some(move_),
+            flag,
+            generation,
         };
+
+        // pick the slot to replace:
+        // - the entry of the same position, if any
+        // - otherwise the least valuable entry: empty, old or shallow
+        let mut replace = &bucket.entries[0];
+        let mut replace_value = i32::MAX;
+
+        for slot in bucket.entries.iter() {
+            let data = slot.load(Ordering::Relaxed);
+            let entry = TEntry::unpack(data);
+
+            if data != 0 && entry.key == new_entry.key {
+                // keep a deeper entry of the same position from this search, unless the new one is exact
+                if entry.generation == generation
+                    && entry.depth > new_entry.depth + 2
+                    && flag != TFlag::Exact
+                {
+                    return;
+                }
+
+                replace = slot;
+                break;
+            }
+
+            // deep entries from the current search are the most valuable
+            let value = if data == 0 {
+                i32::MIN // empty slot
+            } else {
+                entry.depth as i32 - 8 * entry.age(generation) as i32
+            };
+            if value < replace_value {
+                replace = slot;
+                replace_value = value;
+            }
+        }
+
+        replace.store(new_entry.pack(), Ordering::Relaxed);
     }
 
     pub fn read_entry(
         &self,
         pos: &Chess,
         key: HashKey,
+        ply: usize,
         alpha: i32,
         beta: i32,
         depth: i32,
         pv_move: &mut Option<Move>,
     ) -> Option<i32> {
-        let entry = &self.entries[key.0 as usize % self.entries.len()];
-
-        // make sure the position is the same (note that there can still be collisions)
-        if entry.key == key {
-            if let Some(ref mov) = entry.move_ {
-                // check legality
-                if pos.is_legal(&mov) {
-                    // make sure depth is the same or higher (otherwise information may be incorrect)
-                    if entry.depth as i32 >= depth {
-                        match entry.flag {
-                            TFlag::Exact => return Some(entry.score),
-                            TFlag::Alpha => {
-                                if entry.score <= alpha {
-                                    return Some(alpha);
-                                }
+        let bucket = self.bucket(key);
+
+        for slot in bucket.entries.iter() {
+            let entry = TEntry::unpack(slot.load(Ordering::Relaxed));
+
+            // make sure the position is the same (note that there can still be collisions)
+            // empty slots have no move, so they are discarded by the legality check
+            if entry.key != key.0 as u16 {
+                continue;
+            }
+
+            let score = score_from_tt(entry.score as Value, ply);
+
+            // check legality
+            if let Some(mov) = unpack_move(entry.move_, pos) {
+                // make sure depth is the same or higher (otherwise information may be incorrect)
+                if entry.depth as i32 >= depth {
+                    match entry.flag {
+                        TFlag::Exact => return Some(score),
+                        TFlag::Alpha => {
+                            if score <= alpha {
+                                return Some(alpha);
                             }
-                            TFlag::Beta => {
-                                if entry.score >= beta {
-                                    return Some(beta);
-                                }
+                        }
+                        TFlag::Beta => {
+                            if score >= beta {
+                                return Some(beta);
                             }
                         }
                     }
-
-                    *pv_move = entry.move_.clone();
                 }
+
+                *pv_move = Some(mov);
             }
+
+            break;
         }
 
         None
     }
 }
+
+/// Mate scores are relative to the root (`MATE - ply`), but the same position can be reached at different plies.
+/// In the table they are stored relative to the position instead, so they are valid wherever the entry is found.
+fn score_to_tt(score: Value, ply: usize) -> Value {
+    if score >= MATE_THRESHOLD {
+        score + ply as Value
+    } else if score <= -MATE_THRESHOLD {
+        score - ply as Value
+    } else {
+        score
+    }
+}
+
+/// Inverse of `score_to_tt`, converts a score from the table back to root relative
+fn score_from_tt(score: Value, ply: usize) -> Value {
+    if score >= MATE_THRESHOLD {
+        score - ply as Value
+    } else if score <= -MATE_THRESHOLD {
+        score + ply as Value
+    } else {
+        score
+    }
+}
+
+/// Packs a move into 16 bits
+/// from (6) | to (6) | promotion role (3)
+/// Castling moves are stored as king to rook
+fn pack_move(mov: &Move) -> u16 {
+    match mov.to_uci(CastlingMode::Chess960) {
+        UciMove::Normal {
+            from,
+            to,
+            promotion,
+        } => (from as u16) | (to as u16) << 6 | (promotion.map_or(0, |r| r as u16)) << 12,
+        _ => 0,
+    }
+}
+
+/// Unpacks a move packed with `pack_move`, only if it is legal in the given position
+fn unpack_move(packed: u16, pos: &Chess) -> Option<Move> {
+    let promotion = (packed >> 12) as usize & 0b111;
+
+    UciMove::Normal {
+        from: Square::new((packed & 0b111111) as u32),
+        to: Square::new((packed >> 6 & 0b111111) as u32),
+        promotion: (promotion > 0).then(|| Role::ALL[promotion - 1]),
+    }
+    .to_move(pos)
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defs::{INFINITY, INVALID_MOVE, MATE};
+    use shakmaty::{fen::Fen, zobrist::Zobrist64};
+
+    fn position(fen: &str, mode: CastlingMode) -> Chess {
+        fen.parse::<Fen>().unwrap().into_position(mode).unwrap()
+    }
+
+    /// Keys of the entries in the bucket of the given key, 0 for empty slots
+    fn bucket_keys(tt: &TranspositionTable, key: HashKey) -> Vec<u16> {
+        tt.bucket(key)
+            .entries
+            .iter()
+            .map(|slot| TEntry::unpack(slot.load(Ordering::Relaxed)).key)
+            .collect()
+    }
+
+    #[test]
+    fn test_pack_entry() {
+        for (score, depth, flag, generation) in [
+            (0, 0, TFlag::Alpha, 0),
+            (-1, 1, TFlag::Beta, 1),
+            (-(MATE as i16) + 3, i8::MAX, TFlag::Exact, GENERATIONS - 1),
+            (i16::MAX, 0, TFlag::Exact, GENERATIONS - 1),
+            (-i16::MAX, i8::MAX, TFlag::Alpha, 0),
+        ] {
+            let entry = TEntry {
+                key: 0xbeef,
+                move_: 0xfedc,
+                score,
+                depth,
+                flag,
+                generation,
+            };
+            let unpacked = TEntry::unpack(entry.pack());
+
+            assert_eq!(unpacked.key, entry.key);
+            assert_eq!(unpacked.move_, entry.move_);
+            assert_eq!(unpacked.score, score);
+            assert_eq!(unpacked.depth, depth);
+            assert!(unpacked.flag == flag);
+            assert_eq!(unpacked.generation, generation);
+        }
+    }
+
+    #[test]
+    fn test_generation_wrap() {
+        let tt = TranspositionTable::new(0);
+        for _ in 0..GENERATIONS - 1 {
+            tt.new_search();
+        }
+        assert_eq!(tt.generation.load(Ordering::Relaxed), GENERATIONS - 1);
+
+        tt.new_search();
+        assert_eq!(tt.generation.load(Ordering::Relaxed), 0);
+
+        // an entry of the last generation is one search old
+        let entry = TEntry::unpack(0);
+        let entry = TEntry {
+            generation: GENERATIONS - 1,
+            ..entry
+        };
+        assert_eq!(entry.age(0), 1);
+        assert_eq!(entry.age(GENERATIONS - 1), 0);
+    }
+
+    #[test]
+    fn test_pack_move() {
+        let cases = [
+            // promotions, with and without capture
+            (
+                "4r3/3P4/8/8/8/8/8/k6K w - - 0 1",
+                CastlingMode::Standard,
+                "d7d8q",
+            ),
+            (
+                "4r3/3P4/8/8/8/8/8/k6K w - - 0 1",
+                CastlingMode::Standard,
+                "d7e8n",
+            ),
+            // castling is stored as king to rook
+            (
+                "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1",
+                CastlingMode::Standard,
+                "e1g1",
+            ),
+            (
+                "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1",
+                CastlingMode::Standard,
+                "e8c8",
+            ),
+            // Chess960 castling, to the side where the king lands on the square of the rook too
+            (
+                "4k3/8/8/8/8/8/8/1R2K1R1 w GB - 0 1",
+                CastlingMode::Chess960,
+                "e1g1",
+            ),
+            (
+                "4k3/8/8/8/8/8/8/1R2K1R1 w GB - 0 1",
+                CastlingMode::Chess960,
+                "e1b1",
+            ),
+        ];
+
+        for (fen, mode, uci) in cases {
+            let pos = position(fen, mode);
+            let mov = uci.parse::<UciMove>().unwrap().to_move(&pos).unwrap();
+
+            assert_eq!(
+                unpack_move(pack_move(&mov), &pos),
+                Some(mov.clone()),
+                "{}",
+                uci
+            );
+            if let Move::Castle { rook, .. } = mov {
+                assert_eq!(pack_move(&mov) >> 6 & 0b111111, rook as u16, "{}", uci);
+            }
+        }
+    }
+
+    #[test]
+    fn test_replacement() {
+        // a single bucket
+        let tt = TranspositionTable::new(0);
+        let key = |k: u16| Zobrist64(k as u64);
+
+        tt.write_entry(key(1), 0, INVALID_MOVE, 0, 5, TFlag::Exact);
+        tt.write_entry(key(2), 0, INVALID_MOVE, 0, 3, TFlag::Exact);
+        tt.new_search();
+        tt.write_entry(key(3), 0, INVALID_MOVE, 0, 4, TFlag::Exact);
+        tt.write_entry(key(4), 0, INVALID_MOVE, 0, 6, TFlag::Exact);
+        assert_eq!(bucket_keys(&tt, key(0)), [1, 2, 3, 4]);
+
+        // the oldest and shallowest entry goes first
+        tt.write_entry(key(5), 0, INVALID_MOVE, 0, 1, TFlag::Exact);
+        assert_eq!(bucket_keys(&tt, key(0)), [1, 5, 3, 4]);
+
+        // old entries go before shallower entries of this search
+        tt.write_entry(key(6), 0, INVALID_MOVE, 0, 2, TFlag::Exact);
+        assert_eq!(bucket_keys(&tt, key(0)), [6, 5, 3, 4]);
+
+        // then the shallowest of this search
+        tt.write_entry(key(7), 0, INVALID_MOVE, 0, 8, TFlag::Exact);
+        assert_eq!(bucket_keys(&tt, key(0)), [6, 7, 3, 4]);
+
+        // the same position is replaced in place, unless a much deeper bound of this search is there
+        tt.write_entry(key(4), 0, INVALID_MOVE, 0, 2, TFlag::Beta);
+        assert_eq!(
+            TEntry::unpack(tt.buckets[0].entries[3].load(Ordering::Relaxed)).depth,
+            6
+        );
+        tt.write_entry(key(4), 0, INVALID_MOVE, 0, 2, TFlag::Exact);
+        assert_eq!(
+            TEntry::unpack(tt.buckets[0].entries[3].load(Ordering::Relaxed)).depth,
+            2
+        );
+    }
+
+    #[test]
+    fn test_mate_scores() {
+        for score in [MATE - 1, MATE - 10, -MATE + 2, -MATE + 11, 0, 123, -456] {
+            for ply in [0, 1, 7, 20] {
+                assert_eq!(score_from_tt(score_to_tt(score, ply), ply), score);
+            }
+        }
+
+        // a mate found 3 plies below the root is a mate in 2 plies from the position
+        assert_eq!(score_to_tt(MATE - 5, 3), MATE - 2);
+        assert_eq!(score_to_tt(-MATE + 5, 3), -MATE + 2);
+        // and a mate in 2 plies found at ply 6 is 8 plies from the root
+        assert_eq!(score_from_tt(MATE - 2, 6), MATE - 8);
+        assert_eq!(score_from_tt(-MATE + 2, 6), -MATE + 8);
+        // other scores do not depend on the ply
+        assert_eq!(score_to_tt(MATE_THRESHOLD - 1, 10), MATE_THRESHOLD - 1);
+    }
+
+    #[test]
+    fn test_score_clamp() {
+        let tt = TranspositionTable::new(0);
+        let stored = |tt: &TranspositionTable| {
+            TEntry::unpack(tt.buckets[0].entries[0].load(Ordering::Relaxed)).score
+        };
+
+        // bounds beyond the i16 range are clamped
+        tt.write_entry(Zobrist64(1), 0, INVALID_MOVE, INFINITY, 1, TFlag::Beta);
+        assert_eq!(stored(&tt), i16::MAX);
+        tt.write_entry(Zobrist64(1), 0, INVALID_MOVE, -INFINITY, 1, TFlag::Alpha);
+        assert_eq!(stored(&tt), -i16::MAX);
+        tt.write_entry(Zobrist64(1), 0, INVALID_MOVE, MATE - 1, 1, TFlag::Exact);
+        assert_eq!(stored(&tt), (MATE - 1) as i16);
+    }
+
+    #[test]
+    fn test_hashfull() {
+        let tt = TranspositionTable::new(1);
+        let num_buckets = tt.buckets.len() as u64;
+        assert_eq!(tt.hashfull(), 0);
+
+        // fill every slot of the sampled buckets, the upper bits select the bucket
+        for bucket in 0..(1000 / BUCKET_SIZE) as u64 {
+            for slot in 0..BUCKET_SIZE as u64 {
+                let key = Zobrist64((((bucket << 48) / num_buckets) << 16) | (slot + 1));
+                tt.write_entry(key, 0, INVALID_MOVE, 0, 1, TFlag::Exact);
+            }
+        }
+        assert_eq!(tt.hashfull(), 1000);
+
+        // entries of previous searches are not counted
+        tt.new_search();
+        assert_eq!(tt.hashfull(), 0);
+
+        tt.clear();
+        assert_eq!(tt.hashfull(), 0);
+        assert!(tt.buckets[0].entries[0].load(Ordering::Relaxed) == 0);
+    }
+}
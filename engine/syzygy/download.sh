@@ -0,0 +1,10 @@
+#!/bin/sh
+# Downloads the small Syzygy tables used by the tablebase tests into this directory
+set -e
+cd "$(dirname "$0")"
+
+for table in KQvK KRvK KNvK KPvK; do
+    for ext in rtbw rtbz; do
+        curl -sSfO "https://tablebase.lichess.ovh/tables/standard/3-4-5/$table.$ext"
+    done
+done
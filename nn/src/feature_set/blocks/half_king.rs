@@ -0,0 +1,244 @@
+use super::all::correct_square;
+use super::FeatureBlock;
+use shakmaty::{Board, Color, File, Move, Role, Square};
+
+/// Pieces encoded relative to the king
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HalfKingPieces {
+    /// Every piece except the kings (HalfKP)
+    NoKings,
+    /// Every piece, both kings share a plane since the own king is already given by the bucket (HalfKAv2)
+    All,
+}
+
+/// Bucket of each king square. The board is mirrored so the king is always on files E-H,
+/// so only those are stored, indexed by `rank * 4 + (file - E)`
+pub type KingBuckets = [u8; 32];
+
+/// One bucket per king square
+#[rustfmt::skip]
+pub const KING_BUCKETS_32: KingBuckets = [
+     0,  1,  2,  3,
+     4,  5,  6,  7,
+     8,  9, 10, 11,
+    12, 13, 14, 15,
+    16, 17, 18, 19,
+    20, 21, 22, 23,
+    24, 25, 26, 27,
+    28, 29, 30, 31,
+];
+
+/// Fine grained buckets where the king usually is, and coarse ones when it is up the board
+#[rustfmt::skip]
+pub const KING_BUCKETS_8: KingBuckets = [
+    0, 1, 2, 3,
+    4, 4, 5, 5,
+    6, 6, 6, 6,
+    6, 6, 6, 6,
+    7, 7, 7, 7,
+    7, 7, 7, 7,
+    7, 7, 7, 7,
+    7, 7, 7, 7,
+];
+
+/// A block of features that encodes every piece (role, color and square) for each bucket of the perspective's king square.
+/// The board is mirrored horizontally when the king is on files A-D
+#[derive(Debug)]
+pub struct HalfKingBlock {
+    pieces: HalfKingPieces,
+    buckets: &'static KingBuckets,
+    num_buckets: u16,
+}
+
+impl HalfKingBlock {
+    pub fn new(pieces: HalfKingPieces, buckets: &'static KingBuckets) -> Self {
+        Self {
+            pieces,
+            buckets,
+            num_buckets: *buckets.iter().max().unwrap() as u16 + 1,
+        }
+    }
+
+    /// Number of piece planes in each bucket
+    #[inline(always)]
+    fn num_planes(&self) -> u16 {
+        match self.pieces {
+            HalfKingPieces::NoKings => 10,
+            HalfKingPieces::All => 11,
+        }
+    }
+
+    /// Bucket of the king square and whether the board is mirrored, from the perspective's point of view
+    #[inline(always)]
+    fn king_bucket(&self, king_square: Square, perspective: Color) -> (u16, bool) {
+        let king_square = correct_square(king_square, perspective);
+        let mirror = king_square.file() < File::E;
+        let king_square = if mirror {
+            king_square.flip_horizontal()
+        } else {
+            king_square
+        };
+
+        let index =
+            king_square.rank() as usize * 4 + (king_square.file() as usize - File::E as usize);
+
+        (self.buckets[index] as u16, mirror)
+    }
+
+    /// Square of the perspective's king. When the king itself is being added it is not on the board yet
+    #[inline(always)]
+    fn king_square(
+        board: &Board,
+        piece_square: Square,
+        piece_role: Role,
+        piece_color: Color,
+        perspective: Color,
+    ) -> Square {
+        if piece_role == Role::King && piece_color == perspective {
+            piece_square
+        } else {
+            board.king_of(perspective).unwrap()
+        }
+    }
+
+    /// Computes the index for a given piece. This can be done since the block is piece-independent (given the king)
+    #[inline(always)]
+    fn compute_indexes(
+        &self,
+        king_square: Square,
+        piece_square: Square,
+        piece_role: Role,
+        piece_color: Color,
+        perspective: Color,
+        features: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        let plane = match (piece_role, self.pieces) {
+            (Role::King, HalfKingPieces::NoKings) => return,
+            (Role::King, HalfKingPieces::All) => 10,
+            _ => (piece_role as u16 - 1) * 2 + (piece_color != perspective) as u16,
+        };
+
+        let (bucket, mirror) = self.king_bucket(king_square, perspective);
+        let piece_square = correct_square(piece_square, perspective);
+        let piece_square = if mirror {
+            piece_square.flip_horizontal()
+        } else {
+            piece_square
+        };
+
+        let num_planes = self.num_planes();
+
+        features.push(
+            offset
+                + bucket * 64 * num_planes
+                + (piece_square.file() as u16 * 8 + piece_square.rank() as u16) * num_planes
+                + plane,
+        );
+    }
+}
+
+impl FeatureBlock for HalfKingBlock {
+    fn size(&self) -> u16 {
+        self.num_buckets * 64 * self.num_planes()
+    }
+
+    fn requires_refresh(
+        &self,
+        _board: &Board,
+        mov: &Move,
+        turn: Color,
+        perspective: Color,
+    ) -> bool {
+        if turn != perspective || mov.role() != Role::King {
+            return false;
+        }
+
+        match mov {
+            // the king is off the board while the rook is moved
+            Move::Castle { .. } => true,
+            _ => {
+                self.king_bucket(mov.from().unwrap(), perspective)
+                    != self.king_bucket(mov.to(), perspective)
+            }
+        }
+    }
+
+    fn num_refresh_buckets(&self) -> u16 {
+        // the mirrored and not mirrored halves of each bucket
+        self.num_buckets * 2
+    }
+
+    fn refresh_bucket(&self, board: &Board, perspective: Color) -> u16 {
+        let (bucket, mirror) = self.king_bucket(board.king_of(perspective).unwrap(), perspective);
+
+        bucket * 2 + mirror as u16
+    }
+
+    fn active_features(
+        &self,
+        board: &Board,
+        _turn: Color,
+        perspective: Color,
+        features: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        let king_square = board.king_of(perspective).unwrap();
+
+        for (piece_square, piece) in board.clone().into_iter() {
+            self.compute_indexes(
+                king_square,
+                piece_square,
+                piece.role,
+                piece.color,
+                perspective,
+                features,
+                offset,
+            );
+        }
+    }
+
+    fn features_on_add(
+        &self,
+        board: &Board,
+        piece_square: Square,
+        piece_role: Role,
+        piece_color: Color,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        _rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        self.compute_indexes(
+            Self::king_square(board, piece_square, piece_role, piece_color, perspective),
+            piece_square,
+            piece_role,
+            piece_color,
+            perspective,
+            add_feats, // ←
+            offset,
+        );
+    }
+
+    fn features_on_remove(
+        &self,
+        board: &Board,
+        piece_square: Square,
+        piece_role: Role,
+        piece_color: Color,
+        perspective: Color,
+        _add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        self.compute_indexes(
+            Self::king_square(board, piece_square, piece_role, piece_color, perspective),
+            piece_square,
+            piece_role,
+            piece_color,
+            perspective,
+            rem_feats, // ←
+            offset,
+        );
+    }
+}
@@ -55,6 +55,14 @@ impl FeatureBlock for KingBlock {
         turn == perspective && board.role_at(mov.from().unwrap()).unwrap() == Role::King
     }
 
+    fn num_refresh_buckets(&self) -> u16 {
+        64
+    }
+
+    fn refresh_bucket(&self, board: &Board, perspective: Color) -> u16 {
+        correct_square(board.king_of(perspective).unwrap(), perspective) as u16
+    }
+
     fn active_features(
         &self,
         board: &Board,
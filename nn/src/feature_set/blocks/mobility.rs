@@ -27,13 +27,14 @@ impl MobilityBitsetBlock {
     pub fn update_features(
         prev_board: &Board,
         next_board: &Board,
+        changed: Square,
         perspective: Color,
         add_feats: &mut Vec<u16>,
         rem_feats: &mut Vec<u16>,
         offset: u16,
     ) {
-        let mobility_prev = mobility_by_role(prev_board);
-        let mobility_next = mobility_by_role(next_board);
+        let (mobility_prev, mobility_next) =
+            mobility_by_role_changed(prev_board, next_board, changed);
 
         for &role in Role::ALL.iter() {
             for &color in Color::ALL.iter() {
@@ -103,6 +104,7 @@ impl FeatureBlock for MobilityBitsetBlock {
         Self::update_features(
             &board,
             &next_board,
+            piece_square,
             perspective,
             add_feats,
             rem_feats,
@@ -127,6 +129,7 @@ impl FeatureBlock for MobilityBitsetBlock {
         Self::update_features(
             &board,
             &next_board,
+            piece_square,
             perspective,
             add_feats,
             rem_feats,
@@ -164,13 +167,14 @@ impl MobilityCountsBlock {
     pub fn update_features(
         prev_board: &Board,
         next_board: &Board,
+        changed: Square,
         perspective: Color,
         add_feats: &mut Vec<u16>,
         rem_feats: &mut Vec<u16>,
         offset: u16,
     ) {
-        let mobility_prev = mobility_by_role(prev_board);
-        let mobility_next = mobility_by_role(next_board);
+        let (mobility_prev, mobility_next) =
+            mobility_by_role_changed(prev_board, next_board, changed);
 
         for &role in Role::ALL.iter() {
             for &color in Color::ALL.iter() {
@@ -250,6 +254,7 @@ impl FeatureBlock for MobilityCountsBlock {
         Self::update_features(
             &board,
             &next_board,
+            piece_square,
             perspective,
             add_feats,
             rem_feats,
@@ -274,6 +279,7 @@ impl FeatureBlock for MobilityCountsBlock {
         Self::update_features(
             &board,
             &next_board,
+            piece_square,
             perspective,
             add_feats,
             rem_feats,
@@ -292,6 +298,85 @@ fn mobility_by_role(board: &Board) -> ByRole<ByColor<Bitboard>> {
     all
 }
 
+/// Same as `mobility_by_role` for two boards that only differ in the `changed` square,
+/// but only computes the mobility of the pieces that may have changed.
+/// Roles and colors whose mobility can not change are left empty in both
+fn mobility_by_role_changed(
+    prev_board: &Board,
+    next_board: &Board,
+    changed: Square,
+) -> (ByRole<ByColor<Bitboard>>, ByRole<ByColor<Bitboard>>) {
+    let mut prev = ByRole::new_with(|_| ByColor::new_with(|_| Bitboard(0)));
+    let mut next = ByRole::new_with(|_| ByColor::new_with(|_| Bitboard(0)));
+
+    let affected = affected_pieces(prev_board, changed) | affected_pieces(next_board, changed);
+
+    // only the roles and colors with at least one affected piece have to be computed
+    let mut touched = ByRole::new_with(|_| ByColor::new_with(|_| false));
+    for board in [prev_board, next_board] {
+        for sq in affected & board.occupied() {
+            let piece = board.piece_at(sq).unwrap();
+            *touched.get_mut(piece.role).get_mut(piece.color) = true;
+        }
+    }
+
+    for (sq, piece) in prev_board.clone().into_iter() {
+        // pieces that are not affected are the same in both boards, and so is their mobility
+        if !affected.contains(sq) && *touched.get(piece.role).get(piece.color) {
+            let mobility = mobility(prev_board, sq);
+            *prev.get_mut(piece.role).get_mut(piece.color) |= mobility;
+            *next.get_mut(piece.role).get_mut(piece.color) |= mobility;
+        }
+    }
+
+    for (board, all) in [(prev_board, &mut prev), (next_board, &mut next)] {
+        for sq in affected & board.occupied() {
+            let piece = board.piece_at(sq).unwrap();
+            *all.get_mut(piece.role).get_mut(piece.color) |= mobility(board, sq);
+        }
+    }
+
+    (prev, next)
+}
+
+/// Squares of the pieces whose mobility may change if the `changed` square is modified:
+/// - the piece in the square itself
+/// - pieces that attack the square or, for pawns, can step into it
+/// - the kings, since the squares they can move to depend on the attacks of every enemy piece
+/// - every piece of a color if the square can give check or pin a piece to its king
+fn affected_pieces(board: &Board, changed: Square) -> Bitboard {
+    let occupied = board.occupied();
+    let mut affected = Bitboard::from(changed);
+
+    for (sq, piece) in board.clone().into_iter() {
+        if attacks::attacks(sq, piece, occupied).contains(changed)
+            || (piece.role == Role::Pawn && sq.offset(piece.color.fold_wb(8, -8)) == Some(changed))
+        {
+            affected.set(sq, true);
+        }
+    }
+
+    for &color in Color::ALL.iter() {
+        match board.king_of(color) {
+            Some(king_sq) => {
+                affected.set(king_sq, true);
+
+                // lines and knight jumps from the king
+                let king_lines =
+                    attacks::queen_attacks(king_sq, Bitboard(0)) | attacks::knight_attacks(king_sq);
+
+                if king_lines.contains(changed) {
+                    affected |= board.by_color(color);
+                }
+            }
+            // without the king no piece has mobility, so all of them change when it comes back
+            None => affected |= board.by_color(color),
+        }
+    }
+
+    affected
+}
+
 pub fn mobility(board: &Board, sq: Square) -> Bitboard {
     let piece = board.piece_at(sq).unwrap();
     let occupied = board.occupied();
@@ -357,7 +442,7 @@ pub fn mobility(board: &Board, sq: Square) -> Bitboard {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use shakmaty::{fen::Fen, Board, CastlingMode, Chess, Position};
+    use shakmaty::{fen::Fen, Board, CastlingMode, Chess, Position, Rank};
 
     #[test]
     fn test_default() {
@@ -487,4 +572,111 @@ mod tests {
         // check that the king can't move to squares that would make it go into check
         assert_eq!(mobility(pos.board(), Square::G8).count(), 1);
     }
+
+    /// Checks that the incremental mobility matches the full recomputation
+    fn check_changed(prev_board: &Board, next_board: &Board, changed: Square) {
+        let full_prev = mobility_by_role(prev_board);
+        let full_next = mobility_by_role(next_board);
+        let (inc_prev, inc_next) = mobility_by_role_changed(prev_board, next_board, changed);
+
+        for &role in Role::ALL.iter() {
+            for &color in Color::ALL.iter() {
+                let full_prev = *full_prev.get(role).get(color);
+                let full_next = *full_next.get(role).get(color);
+                let inc_prev = *inc_prev.get(role).get(color);
+                let inc_next = *inc_next.get(role).get(color);
+
+                if inc_prev != full_prev || inc_next != full_next {
+                    // skipped, so it must not have changed
+                    assert!(inc_prev.is_empty() && inc_next.is_empty());
+                    assert_eq!(full_prev, full_next);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_incremental() {
+        use crate::feature_set::build::build_feature_set;
+        use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let feature_set = build_feature_set("mb+mc");
+
+        for _ in 0..20 {
+            let mut pos = Chess::default();
+
+            for _ in 0..120 {
+                let moves = pos.legal_moves();
+                let Some(mov) = moves.choose(&mut rng) else {
+                    break;
+                };
+
+                // change a random square: remove the piece or add a new one
+                let board = pos.board();
+                let sq = Square::new(rng.gen_range(0..64));
+                let mut changed_board = board.clone();
+                if board.piece_at(sq).is_some() {
+                    changed_board.discard_piece_at(sq);
+                } else if sq.rank() != Rank::First && sq.rank() != Rank::Eighth {
+                    let role = *[
+                        Role::Pawn,
+                        Role::Knight,
+                        Role::Bishop,
+                        Role::Rook,
+                        Role::Queen,
+                    ]
+                    .choose(&mut rng)
+                    .unwrap();
+                    let color = if rng.gen() {
+                        Color::White
+                    } else {
+                        Color::Black
+                    };
+                    changed_board.set_piece_at(sq, Piece { role, color });
+                }
+                check_changed(board, &changed_board, sq);
+                check_changed(&changed_board, board, sq);
+
+                // and the features of the move against the next position
+                let mut pos_moved = pos.clone();
+                pos_moved.play_unchecked(mov);
+
+                for &perspective in Color::ALL.iter() {
+                    let mut features = vec![];
+                    let mut added_features = vec![];
+                    let mut removed_features = vec![];
+                    feature_set.active_features(board, pos.turn(), perspective, &mut features);
+                    feature_set.changed_features(
+                        board,
+                        mov,
+                        pos.turn(),
+                        perspective,
+                        &mut added_features,
+                        &mut removed_features,
+                    );
+
+                    for x in removed_features {
+                        let index = features.iter().position(|&r| r == x).unwrap();
+                        features.remove(index);
+                    }
+                    features.extend(added_features);
+
+                    let mut truth_features = vec![];
+                    feature_set.active_features(
+                        pos_moved.board(),
+                        pos_moved.turn(),
+                        perspective,
+                        &mut truth_features,
+                    );
+
+                    features.sort();
+                    truth_features.sort();
+                    assert_eq!(features, truth_features);
+                }
+
+                pos = pos_moved;
+            }
+        }
+    }
 }
@@ -1,8 +1,11 @@
 pub mod all;
 pub mod axes;
+pub mod half_king;
 pub mod king;
 pub mod mobility;
 pub mod pairwise;
+pub mod pawn_structure;
+pub mod threats;
 
 use crate::feature_set::blocks::axes::AxesBlock;
 use crate::feature_set::blocks::mobility::MobilityBitsetBlock;
@@ -10,8 +13,11 @@ use crate::feature_set::blocks::mobility::MobilityCountsBlock;
 use crate::feature_set::blocks::pairwise::PairwiseBlock;
 use all::AllBlock;
 use enum_dispatch::enum_dispatch;
+use half_king::HalfKingBlock;
 use king::KingBlock;
+use pawn_structure::PawnStructureBlock;
 use shakmaty::{Board, Color, Move, Role, Square};
+use threats::ThreatsBlock;
 
 /// A block of features
 #[enum_dispatch]
@@ -21,8 +27,11 @@ pub enum FeatureBlocks {
     AxesBlock,
     PairwiseBlock,
     KingBlock,
+    HalfKingBlock,
     MobilityBitsetBlock,
     MobilityCountsBlock,
+    ThreatsBlock,
+    PawnStructureBlock,
 }
 
 #[enum_dispatch(FeatureBlocks)]
@@ -41,6 +50,17 @@ pub trait FeatureBlock {
         false
     }
 
+    /// Number of different refresh buckets, see `refresh_bucket`
+    fn num_refresh_buckets(&self) -> u16 {
+        1
+    }
+
+    /// Groups positions that are likely to share many features after a refresh (e.g. the king square),
+    /// used to pick the cached accumulator to refresh from
+    fn refresh_bucket(&self, _board: &Board, _perspective: Color) -> u16 {
+        0
+    }
+
     /// Computes the initial features for the given board and perspective (potentially slow)
     fn active_features(
         &self,
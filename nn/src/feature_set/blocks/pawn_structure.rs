@@ -0,0 +1,296 @@
+use super::{all::correct_square, FeatureBlock};
+use shakmaty::{attacks, Bitboard, Board, Color, File, Rank, Role, Square};
+
+/// Kinds of pawns in the structure, a pawn may be of more than one kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PawnKind {
+    /// No enemy pawns in front of it, on the same or adjacent files
+    Passed = 0,
+    /// No friendly pawns on adjacent files
+    Isolated = 1,
+    /// Another friendly pawn on the same file
+    Doubled = 2,
+    /// Not isolated, but no friendly pawns beside or behind it on adjacent files,
+    /// and the square in front of it is attacked by an enemy pawn
+    Backward = 3,
+}
+
+impl PawnKind {
+    pub const ALL: [PawnKind; 4] = [
+        PawnKind::Passed,
+        PawnKind::Isolated,
+        PawnKind::Doubled,
+        PawnKind::Backward,
+    ];
+}
+
+/// A block of features that encodes the square of each passed, isolated, doubled and backward pawn
+#[derive(Debug)]
+pub struct PawnStructureBlock {}
+
+impl PawnStructureBlock {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn compute_index(
+        kind: PawnKind,
+        sq: Square,
+        color: Color,
+        perspective: Color,
+        offset: u16,
+    ) -> u16 {
+        let sq = correct_square(sq, perspective);
+        let color = (color != perspective) as u16;
+
+        offset + kind as u16 * 128 + color * 64 + sq as u16
+    }
+
+    #[inline(always)]
+    pub fn update_features(
+        prev_board: &Board,
+        next_board: &Board,
+        changed: Square,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        // the kind of a pawn only depends on the pawns on its file and the adjacent ones
+        let squares = files_around(changed.file());
+
+        for &color in Color::ALL.iter() {
+            let prev_pawns = prev_board.pawns() & prev_board.by_color(color);
+            let next_pawns = next_board.pawns() & next_board.by_color(color);
+
+            for sq in (prev_pawns | next_pawns) & squares {
+                let prev_kinds = if prev_pawns.contains(sq) {
+                    kinds_mask(prev_board, sq, color)
+                } else {
+                    0
+                };
+                let next_kinds = if next_pawns.contains(sq) {
+                    kinds_mask(next_board, sq, color)
+                } else {
+                    0
+                };
+
+                for kind in PawnKind::ALL {
+                    let bit = 1 << kind as u8;
+                    if prev_kinds & !next_kinds & bit != 0 {
+                        rem_feats.push(Self::compute_index(kind, sq, color, perspective, offset));
+                    } else if next_kinds & !prev_kinds & bit != 0 {
+                        add_feats.push(Self::compute_index(kind, sq, color, perspective, offset));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FeatureBlock for PawnStructureBlock {
+    fn size(&self) -> u16 {
+        4 * 2 * 64
+    }
+
+    fn active_features(
+        &self,
+        board: &Board,
+        _turn: Color,
+        perspective: Color,
+        features: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        for &color in Color::ALL.iter() {
+            for sq in board.pawns() & board.by_color(color) {
+                let kinds = kinds_mask(board, sq, color);
+
+                for kind in PawnKind::ALL {
+                    if kinds & (1 << kind as u8) != 0 {
+                        features.push(Self::compute_index(kind, sq, color, perspective, offset));
+                    }
+                }
+            }
+        }
+    }
+
+    fn features_on_add(
+        &self,
+        board: &Board,
+        piece_square: Square,
+        piece_role: Role,
+        piece_color: Color,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        // other pieces do not change the structure
+        if piece_role != Role::Pawn {
+            return;
+        }
+
+        let mut next_board = board.clone();
+        next_board.set_piece_at(piece_square, piece_color.pawn());
+
+        Self::update_features(
+            &board,
+            &next_board,
+            piece_square,
+            perspective,
+            add_feats,
+            rem_feats,
+            offset,
+        );
+    }
+
+    fn features_on_remove(
+        &self,
+        board: &Board,
+        piece_square: Square,
+        piece_role: Role,
+        _piece_color: Color,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        // other pieces do not change the structure
+        if piece_role != Role::Pawn {
+            return;
+        }
+
+        let mut next_board = board.clone();
+        next_board.discard_piece_at(piece_square);
+
+        Self::update_features(
+            &board,
+            &next_board,
+            piece_square,
+            perspective,
+            add_feats,
+            rem_feats,
+            offset,
+        );
+    }
+}
+
+/// Kinds of the pawn of the given color in the square
+pub fn pawn_kinds(board: &Board, sq: Square, color: Color) -> Vec<PawnKind> {
+    let kinds = kinds_mask(board, sq, color);
+
+    PawnKind::ALL
+        .into_iter()
+        .filter(|&kind| kinds & (1 << kind as u8) != 0)
+        .collect()
+}
+
+/// Same as `pawn_kinds`, one bit per kind, so the hot path does not allocate
+fn kinds_mask(board: &Board, sq: Square, color: Color) -> u8 {
+    let own_pawns = board.pawns() & board.by_color(color);
+    let enemy_pawns = board.pawns() & board.by_color(color.other());
+    let adjacent = adjacent_files(sq.file());
+    let ahead = ranks_ahead(sq.rank(), color);
+
+    let mut kinds = 0;
+
+    if (enemy_pawns & files_around(sq.file()) & ahead).is_empty() {
+        kinds |= 1 << PawnKind::Passed as u8;
+    }
+
+    if (own_pawns & adjacent).is_empty() {
+        kinds |= 1 << PawnKind::Isolated as u8;
+    } else if (own_pawns & adjacent & !ahead).is_empty() {
+        // pawns never stand on the last rank, so there is always a square in front
+        let stop = sq.offset(color.fold_wb(8, -8)).unwrap();
+
+        if !(attacks::pawn_attacks(color, stop) & enemy_pawns).is_empty() {
+            kinds |= 1 << PawnKind::Backward as u8;
+        }
+    }
+
+    if !(own_pawns & Bitboard::from_file(sq.file()) & !Bitboard::from(sq)).is_empty() {
+        kinds |= 1 << PawnKind::Doubled as u8;
+    }
+
+    kinds
+}
+
+/// Files next to the given one
+fn adjacent_files(file: File) -> Bitboard {
+    let mut files = Bitboard(0);
+    if file > File::A {
+        files |= Bitboard::from_file(File::new(file as u32 - 1));
+    }
+    if file < File::H {
+        files |= Bitboard::from_file(File::new(file as u32 + 1));
+    }
+    files
+}
+
+/// The given file and the ones next to it
+fn files_around(file: File) -> Bitboard {
+    adjacent_files(file) | Bitboard::from_file(file)
+}
+
+/// Ranks strictly in front of the given one, from the point of view of the color
+fn ranks_ahead(rank: Rank, color: Color) -> Bitboard {
+    let mut ranks = Bitboard(0);
+    for &other in Rank::ALL.iter() {
+        if color.fold_wb(other > rank, other < rank) {
+            ranks |= Bitboard::from_rank(other);
+        }
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode, Chess, Position};
+
+    #[test]
+    fn test_default() {
+        let board = Board::default();
+
+        for sq in board.pawns() {
+            let color = board.color_at(sq).unwrap();
+            assert!(pawn_kinds(&board, sq, color).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_kinds() {
+        use PawnKind::*;
+
+        let pos = Fen::from_ascii(b"4k3/p4p2/3p4/2p5/4P2P/1P5P/1P6/4K3 w - - 0 1")
+            .unwrap()
+            .into_position::<Chess>(CastlingMode::Standard)
+            .unwrap();
+        let board = pos.board();
+
+        // white pawns
+        assert_eq!(
+            pawn_kinds(board, Square::B2, Color::White),
+            vec![Isolated, Doubled]
+        );
+        assert_eq!(
+            pawn_kinds(board, Square::B3, Color::White),
+            vec![Isolated, Doubled]
+        );
+        assert_eq!(pawn_kinds(board, Square::E4, Color::White), vec![Isolated]);
+        assert_eq!(
+            pawn_kinds(board, Square::H3, Color::White),
+            vec![Passed, Isolated, Doubled]
+        );
+        assert_eq!(
+            pawn_kinds(board, Square::H4, Color::White),
+            vec![Passed, Isolated, Doubled]
+        );
+        // black pawns
+        assert_eq!(pawn_kinds(board, Square::A7, Color::Black), vec![Isolated]);
+        assert_eq!(pawn_kinds(board, Square::C5, Color::Black), vec![]);
+        assert_eq!(pawn_kinds(board, Square::D6, Color::Black), vec![Backward]);
+        assert_eq!(pawn_kinds(board, Square::F7, Color::Black), vec![Isolated]);
+    }
+}
@@ -0,0 +1,276 @@
+use super::{all::correct_square, FeatureBlock};
+use shakmaty::{attacks, Bitboard, Board, ByColor, ByRole, Color, Piece, Role, Square};
+
+/// A block of features where each feature is a piece (role and color) attacking a square
+/// occupied by another piece (role and color), like Stockfish's threat inputs.
+/// Attacks to pieces of the same color (defenses) are included too.
+/// A feature is active if any piece of the attacker role and color attacks the square
+#[derive(Debug)]
+pub struct ThreatsBlock {}
+
+impl ThreatsBlock {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn compute_index(
+        attacker: Piece,
+        attacked: Piece,
+        attacked_square: Square,
+        perspective: Color,
+        offset: u16,
+    ) -> u16 {
+        let attacker = (attacker.role as u16 - 1) * 2 + (attacker.color != perspective) as u16;
+        let attacked = (attacked.role as u16 - 1) * 2 + (attacked.color != perspective) as u16;
+        let sq = correct_square(attacked_square, perspective);
+
+        offset + (attacker * 12 + attacked) * 64 + sq as u16
+    }
+
+    #[inline(always)]
+    pub fn update_features(
+        prev_board: &Board,
+        next_board: &Board,
+        changed: Square,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        // only the pieces attacking the changed square can change their threats: the square may now be
+        // (un)occupied, or a slider ray may be blocked or discovered through it.
+        // The changed square does not block its own attackers, so they are the same in both boards
+        let occupied = prev_board.occupied();
+        let affected = prev_board.attacks_to(changed, Color::White, occupied)
+            | prev_board.attacks_to(changed, Color::Black, occupied)
+            | Bitboard::from(changed);
+
+        let mut touched = ByRole::new_with(|_| ByColor::new_with(|_| false));
+        for board in [prev_board, next_board] {
+            for sq in affected & board.occupied() {
+                let piece = board.piece_at(sq).unwrap();
+                *touched.get_mut(piece.role).get_mut(piece.color) = true;
+            }
+        }
+
+        for &role in Role::ALL.iter() {
+            for &color in Color::ALL.iter() {
+                if !*touched.get(role).get(color) {
+                    continue;
+                }
+
+                let attacker = Piece { role, color };
+                let threats_prev = threats(prev_board, attacker);
+                let threats_next = threats(next_board, attacker);
+
+                // the rest of the squares keep the same attacked piece
+                let squares = (threats_prev ^ threats_next)
+                    | ((threats_prev | threats_next) & Bitboard::from(changed));
+
+                for sq in squares {
+                    let feature_prev = threats_prev.contains(sq).then(|| {
+                        let attacked = prev_board.piece_at(sq).unwrap();
+                        Self::compute_index(attacker, attacked, sq, perspective, offset)
+                    });
+                    let feature_next = threats_next.contains(sq).then(|| {
+                        let attacked = next_board.piece_at(sq).unwrap();
+                        Self::compute_index(attacker, attacked, sq, perspective, offset)
+                    });
+
+                    if feature_prev != feature_next {
+                        rem_feats.extend(feature_prev);
+                        add_feats.extend(feature_next);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FeatureBlock for ThreatsBlock {
+    fn size(&self) -> u16 {
+        (6 * 2) * (6 * 2) * 64
+    }
+
+    fn active_features(
+        &self,
+        board: &Board,
+        _turn: Color,
+        perspective: Color,
+        features: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        for &role in Role::ALL.iter() {
+            for &color in Color::ALL.iter() {
+                let attacker = Piece { role, color };
+
+                for sq in threats(board, attacker) {
+                    let attacked = board.piece_at(sq).unwrap();
+                    features.push(Self::compute_index(
+                        attacker,
+                        attacked,
+                        sq,
+                        perspective,
+                        offset,
+                    ));
+                }
+            }
+        }
+    }
+
+    fn features_on_add(
+        &self,
+        board: &Board,
+        piece_square: Square,
+        piece_role: Role,
+        piece_color: Color,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        let mut next_board = board.clone();
+        next_board.set_piece_at(
+            piece_square,
+            Piece {
+                role: piece_role,
+                color: piece_color,
+            },
+        );
+
+        Self::update_features(
+            &board,
+            &next_board,
+            piece_square,
+            perspective,
+            add_feats,
+            rem_feats,
+            offset,
+        );
+    }
+
+    fn features_on_remove(
+        &self,
+        board: &Board,
+        piece_square: Square,
+        _piece_role: Role,
+        _piece_color: Color,
+        perspective: Color,
+        add_feats: &mut Vec<u16>,
+        rem_feats: &mut Vec<u16>,
+        offset: u16,
+    ) {
+        let mut next_board = board.clone();
+        next_board.discard_piece_at(piece_square);
+
+        Self::update_features(
+            &board,
+            &next_board,
+            piece_square,
+            perspective,
+            add_feats,
+            rem_feats,
+            offset,
+        );
+    }
+}
+
+/// Occupied squares attacked by any piece of the given role and color
+pub fn threats(board: &Board, attacker: Piece) -> Bitboard {
+    let occupied = board.occupied();
+    let mut threats = Bitboard(0);
+
+    for sq in board.by_piece(attacker) {
+        threats |= attacks::attacks(sq, attacker, occupied);
+    }
+
+    threats & occupied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::{fen::Fen, CastlingMode, Chess, Position};
+
+    #[test]
+    fn test_default() {
+        let board = Board::default();
+
+        let white_knight = Piece {
+            role: Role::Knight,
+            color: Color::White,
+        };
+        let white_rook = Piece {
+            role: Role::Rook,
+            color: Color::White,
+        };
+
+        // knights defend the pawns in front
+        assert_eq!(
+            threats(&board, white_knight),
+            Bitboard::from(Square::D2) | Bitboard::from(Square::E2)
+        );
+        // rooks only defend their neighbours
+        assert_eq!(
+            threats(&board, white_rook),
+            Bitboard::from(Square::A2)
+                | Bitboard::from(Square::B1)
+                | Bitboard::from(Square::G1)
+                | Bitboard::from(Square::H2)
+        );
+    }
+
+    #[test]
+    fn test_discovered() {
+        let pos = Fen::from_ascii(b"4k3/8/4r3/8/4N3/8/8/4K3 w - - 0 1")
+            .unwrap()
+            .into_position::<Chess>(CastlingMode::Standard)
+            .unwrap();
+        let black_rook = Piece {
+            role: Role::Rook,
+            color: Color::Black,
+        };
+
+        // the knight blocks the rook
+        assert_eq!(
+            threats(pos.board(), black_rook),
+            Bitboard::from(Square::E4) | Bitboard::from(Square::E8)
+        );
+
+        // moving the knight discovers the attack to the king
+        let mut add_feats = vec![];
+        let mut rem_feats = vec![];
+        let mut board = pos.board().clone();
+        ThreatsBlock::new().features_on_remove(
+            &board,
+            Square::E4,
+            Role::Knight,
+            Color::White,
+            Color::White,
+            &mut add_feats,
+            &mut rem_feats,
+            0,
+        );
+        board.discard_piece_at(Square::E4);
+
+        let white_king = board.piece_at(Square::E1).unwrap();
+        let white_knight = Piece {
+            role: Role::Knight,
+            color: Color::White,
+        };
+        assert!(add_feats.contains(&ThreatsBlock::compute_index(
+            black_rook,
+            white_king,
+            Square::E1,
+            Color::White,
+            0
+        )));
+        assert!(rem_feats.contains(&ThreatsBlock::compute_index(
+            black_rook,
+            white_knight,
+            Square::E4,
+            Color::White,
+            0
+        )));
+    }
+}
@@ -1,25 +1,38 @@
 use super::FeatureSet;
 use crate::feature_set::blocks::{
     all::AllBlock,
+    half_king::{HalfKingBlock, HalfKingPieces, KING_BUCKETS_32, KING_BUCKETS_8},
     mobility::{MobilityBitsetBlock, MobilityCountsBlock},
     pairwise::PairwiseBlock,
+    pawn_structure::PawnStructureBlock,
+    threats::ThreatsBlock,
     FeatureBlocks,
 };
 
 /// Build a feature set from its name.
 /// Feature set names are a list of feature block names separated by '+'.
 pub fn build_feature_set(name: &str) -> FeatureSet {
+    try_build_feature_set(name).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Same as `build_feature_set`, but returns an error if a block is unknown
+pub fn try_build_feature_set(name: &str) -> Result<FeatureSet, String> {
     // split name by +
     // each is a block
-    FeatureSet::sum_of(name.split('+').map(|block| get_block(block)).collect())
+    let blocks = name
+        .split('+')
+        .map(|block| get_block(block).ok_or(format!("Unknown NNUE model feature block: {}", block)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(FeatureSet::sum_of(blocks))
 }
 
 /// Get a feature block from its name
-fn get_block(name: &str) -> FeatureBlocks {
+fn get_block(name: &str) -> Option<FeatureBlocks> {
     use crate::feature_set::axis::Axis;
     use crate::feature_set::blocks::axes::*;
 
-    match name {
+    let block = match name {
         // all
         "hv" => FeatureBlocks::AllBlock(AllBlock::new()), // legacy name
         "all" => FeatureBlocks::AllBlock(AllBlock::new()),
@@ -34,9 +47,30 @@ fn get_block(name: &str) -> FeatureBlocks {
         // mobility
         "mb" => FeatureBlocks::MobilityBitsetBlock(MobilityBitsetBlock::new()),
         "mc" => FeatureBlocks::MobilityCountsBlock(MobilityCountsBlock::new()),
+        // threats
+        "threats" => FeatureBlocks::ThreatsBlock(ThreatsBlock::new()),
+        // pawn structure
+        "ps" => FeatureBlocks::PawnStructureBlock(PawnStructureBlock::new()),
+        // king relative, with king buckets
+        "hkp" => FeatureBlocks::HalfKingBlock(HalfKingBlock::new(
+            HalfKingPieces::NoKings,
+            &KING_BUCKETS_32,
+        )),
+        "hka" => {
+            FeatureBlocks::HalfKingBlock(HalfKingBlock::new(HalfKingPieces::All, &KING_BUCKETS_32))
+        }
+        "hkp8" => FeatureBlocks::HalfKingBlock(HalfKingBlock::new(
+            HalfKingPieces::NoKings,
+            &KING_BUCKETS_8,
+        )),
+        "hka8" => {
+            FeatureBlocks::HalfKingBlock(HalfKingBlock::new(HalfKingPieces::All, &KING_BUCKETS_8))
+        }
 
-        _ => panic!("Unknown NNUE model feature block: {}", name),
-    }
+        _ => return None,
+    };
+
+    Some(block)
 }
 
 #[cfg(test)]
@@ -74,5 +108,18 @@ mod tests {
         mc: "mc",
         all_mb: "all+mb",
         all_mc: "all+mc",
+
+        hkp: "hkp",
+        hka: "hka",
+        hkp8: "hkp8",
+        hka8: "hka8",
+        all_hka8: "all+hka8",
+        all_hkp_mb: "all+hkp+mb",
+
+        threats: "threats",
+        all_threats: "all+threats",
+
+        ps: "ps",
+        all_ps: "all+ps",
     }
 }
@@ -5,7 +5,7 @@ use shakmaty::{fen::Fen, Chess, Color, Position};
 /// Well crafted feature sets should be able to pass these checks
 #[allow(dead_code)]
 pub(super) fn fs_correctness_checks(feature_set: &FeatureSet) {
-    const FENS: [&str; 22] = [
+    const FENS: [&str; 28] = [
         "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", // startpos
         "4nrk1/3q1pp1/2n1p1p1/8/1P2Q3/7P/PB1N1PP1/2R3K1 w - - 5 26",
         "5r2/1p2ppkp/p2p1nP1/qn6/4P3/2r2B2/1PPQ1PP1/2KR3R w - - 0 21",
@@ -28,13 +28,21 @@ pub(super) fn fs_correctness_checks(feature_set: &FeatureSet) {
         "rn2k2r/pp2npp1/2pp3p/1P2p3/2BbP2q/P1NQ1P2/1BP2P1P/2KR3R b kq - 2 15",
         "r2q1rk1/1b1nbpp1/p1pp1n1p/Pp2p3/1P1PP3/1BP1BN1P/3N1PP1/R2QK2R b KQ - 0 13",
         "2r3k1/1q1nbppp/r3p3/3pP3/pPpP4/P1Q2N2/2RN1PPP/2R4K b - b3 0 23", // en passant
+        // Chess960
+        "bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9",
+        "2nnrbkr/p1qppppp/8/1ppb4/6PP/3PP3/PPP2P2/BQNNRBKR w HEhe - 1 9",
+        "qbbnnrkr/2pp2pp/p7/1p2pp2/8/P3PP2/1PPP1KPP/QBBNNR1R w hf - 0 9",
+        "1nbbnrkr/p1p1ppp1/3p4/1p3P1p/3Pq2P/8/PPP1P1P1/QNBBNRKR w HFhf - 0 9",
+        "1r3kr1/pppppppp/8/8/8/8/PPPPPPPP/1R3KR1 w GBgb - 0 1", // king and rook swap squares
+        "rn2k1r1/pppppppp/8/8/8/8/PPPPPPPP/RN2K1R1 b GAga - 0 1", // king lands where the rook was
     ];
 
     check_mirror(feature_set);
 
     for fen in FENS {
         let fen: Fen = fen.parse().unwrap();
-        let pos: Chess = fen.into_position(shakmaty::CastlingMode::Standard).unwrap();
+        // standard positions are valid Chess960 positions too
+        let pos: Chess = fen.into_position(shakmaty::CastlingMode::Chess960).unwrap();
 
         check_flipped(&pos, feature_set);
         check_changed(&pos, Color::White, feature_set);
@@ -115,11 +123,6 @@ fn check_changed(pos: &Chess, perspective: Color, feature_set: &FeatureSet) {
         let mut pos_moved = pos.clone();
         pos_moved.play_unchecked(&m);
 
-        if feature_set.requires_refresh(&pos.board(), &m, pos.turn(), perspective) {
-            // if this move requires a full refresh, changed_features is invalid
-            continue;
-        }
-
         let mut added_features = vec![];
         let mut removed_features = vec![];
 
@@ -4,7 +4,7 @@ pub mod build;
 mod checks;
 
 use blocks::{FeatureBlock, FeatureBlocks};
-use shakmaty::{Board, Color, File, Move, Piece, Role, Square};
+use shakmaty::{Board, CastlingSide, Color, Move, Piece, Role, Square};
 
 /// A set of features for a neural network
 #[derive(Debug)]
@@ -16,6 +16,9 @@ pub struct FeatureSet {
 impl FeatureSet {
     /// Create a feature set from the sum of feature blocks
     pub fn sum_of(blocks: Vec<FeatureBlocks>) -> Self {
+        // blocks to refresh are tracked in a bitmask
+        assert!(blocks.len() <= 64, "Too many feature blocks");
+
         Self { blocks }
     }
 
@@ -25,7 +28,8 @@ impl FeatureSet {
         self.blocks.iter().map(|b| b.size()).sum::<u16>()
     }
 
-    /// Whether the given move requires a refresh of the features
+    /// Whether the given move requires a refresh of the features of any block.
+    /// Those blocks are recomputed by `changed_features`, so it is still valid
     #[inline(always)]
     pub fn requires_refresh(
         &self,
@@ -34,9 +38,49 @@ impl FeatureSet {
         turn: Color,
         perspective: Color,
     ) -> bool {
+        self.refresh_mask(board, mov, turn, perspective) != 0
+    }
+
+    /// Whether the given move requires a refresh of every block,
+    /// in which case refreshing the whole accumulator is cheaper than `changed_features`
+    #[inline(always)]
+    pub fn requires_full_refresh(
+        &self,
+        board: &Board,
+        mov: &Move,
+        turn: Color,
+        perspective: Color,
+    ) -> bool {
+        self.refresh_mask(board, mov, turn, perspective)
+            .count_ones() as usize
+            == self.blocks.len()
+    }
+
+    /// Bitmask of the blocks (by index) that require a refresh with the given move
+    #[inline(always)]
+    fn refresh_mask(&self, board: &Board, mov: &Move, turn: Color, perspective: Color) -> u64 {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.requires_refresh(board, mov, turn, perspective))
+            .fold(0, |mask, (i, _)| mask | 1 << i)
+    }
+
+    /// Number of different refresh buckets, the combination of the buckets of each block
+    pub fn num_refresh_buckets(&self) -> usize {
         self.blocks
             .iter()
-            .any(|b| b.requires_refresh(board, mov, turn, perspective))
+            .map(|b| b.num_refresh_buckets() as usize)
+            .product()
+    }
+
+    /// Refresh bucket of the board for the given perspective, in `0..num_refresh_buckets()`
+    #[inline(always)]
+    pub fn refresh_bucket(&self, board: &Board, perspective: Color) -> usize {
+        self.blocks.iter().fold(0, |bucket, b| {
+            bucket * b.num_refresh_buckets() as usize
+                + b.refresh_bucket(board, perspective) as usize
+        })
     }
 
     /// Computes the initial features for the given board and perspective (potentially slow)
@@ -56,7 +100,9 @@ impl FeatureSet {
         }
     }
 
-    /// Computes the features that have changed with the given move (hopefully fast)
+    /// Computes the features that have changed with the given move (hopefully fast).
+    /// Blocks that require a refresh remove all their previous features and add the new ones,
+    /// the rest are updated piece by piece
     #[inline(always)]
     pub fn changed_features(
         &self,
@@ -67,6 +113,11 @@ impl FeatureSet {
         add_feats: &mut Vec<u16>,
         rem_feats: &mut Vec<u16>,
     ) {
+        let refresh_mask = self.refresh_mask(board, mov, turn, perspective);
+        if refresh_mask != 0 {
+            self.masked_active_features(board, turn, perspective, refresh_mask, rem_feats);
+        }
+
         let mut board = board.clone();
         let from = mov.from().unwrap();
         let to = mov.to();
@@ -79,6 +130,7 @@ impl FeatureSet {
                 Role::Pawn,
                 who_plays.other(),
                 perspective,
+                refresh_mask,
                 add_feats,
                 rem_feats,
             );
@@ -89,6 +141,7 @@ impl FeatureSet {
                 captured,
                 who_plays.other(),
                 perspective,
+                refresh_mask,
                 add_feats,
                 rem_feats,
             );
@@ -104,6 +157,7 @@ impl FeatureSet {
                     mov.role(),
                     who_plays,
                     perspective,
+                    refresh_mask,
                     add_feats,
                     rem_feats,
                 );
@@ -113,6 +167,7 @@ impl FeatureSet {
                     final_role,
                     who_plays,
                     perspective,
+                    refresh_mask,
                     add_feats,
                     rem_feats,
                 );
@@ -124,6 +179,7 @@ impl FeatureSet {
                     Role::King,
                     who_plays,
                     perspective,
+                    refresh_mask,
                     add_feats,
                     rem_feats,
                 );
@@ -133,33 +189,33 @@ impl FeatureSet {
                     Role::Rook,
                     who_plays,
                     perspective,
+                    refresh_mask,
                     add_feats,
                     rem_feats,
                 );
 
-                let (king_file, rook_file) = if king < rook {
-                    // king side
-                    (File::G, File::F)
-                } else {
-                    // queen side
-                    (File::C, File::D)
-                };
+                // the king and rook always end on the same squares (in Chess960 too),
+                // but they may start anywhere in the back rank, even on the destination of the other piece.
+                // Both are removed before adding them back, so the board is never in an invalid state
+                let side = CastlingSide::from_king_side(king < rook);
 
                 self.add_piece(
                     &mut board,
-                    Square::from_coords(king_file, king.rank()),
+                    Square::from_coords(side.king_to_file(), king.rank()),
                     Role::King,
                     who_plays,
                     perspective,
+                    refresh_mask,
                     add_feats,
                     rem_feats,
                 );
                 self.add_piece(
                     &mut board,
-                    Square::from_coords(rook_file, rook.rank()),
+                    Square::from_coords(side.rook_to_file(), rook.rank()),
                     Role::Rook,
                     who_plays,
                     perspective,
+                    refresh_mask,
                     add_feats,
                     rem_feats,
                 );
@@ -167,6 +223,11 @@ impl FeatureSet {
             _ => unreachable!(),
         }
 
+        if refresh_mask != 0 {
+            // the board is now the one after the move
+            self.masked_active_features(&board, turn.other(), perspective, refresh_mask, add_feats);
+        }
+
         // hacky optimization: remove common features
         // while rem_feats.last().is_some() && add_feats.last() == rem_feats.last() {
         //     add_feats.pop();
@@ -174,7 +235,27 @@ impl FeatureSet {
         // }
     }
 
-    /// Add a piece to the board and update the features
+    /// Computes the active features of the blocks in the mask only
+    #[inline(always)]
+    fn masked_active_features(
+        &self,
+        board: &Board,
+        turn: Color,
+        perspective: Color,
+        mask: u64,
+        features: &mut Vec<u16>,
+    ) {
+        let mut offset = 0;
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            if mask & (1 << i) != 0 {
+                block.active_features(board, turn, perspective, features, offset);
+            }
+            offset += block.size();
+        }
+    }
+
+    /// Add a piece to the board and update the features of the blocks not being refreshed
     #[inline(always)]
     fn add_piece(
         &self,
@@ -183,12 +264,18 @@ impl FeatureSet {
         piece_role: Role,
         piece_color: Color,
         perspective: Color,
+        refresh_mask: u64,
         add_feats: &mut Vec<u16>,
         rem_feats: &mut Vec<u16>,
     ) {
         let mut offset = 0;
 
-        for block in &self.blocks {
+        for (i, block) in self.blocks.iter().enumerate() {
+            if refresh_mask & (1 << i) != 0 {
+                offset += block.size();
+                continue;
+            }
+
             block.features_on_add(
                 board,
                 piece_square,
@@ -211,7 +298,7 @@ impl FeatureSet {
         );
     }
 
-    /// Remove a piece from the board and update the features
+    /// Remove a piece from the board and update the features of the blocks not being refreshed
     #[inline(always)]
     fn remove_piece(
         &self,
@@ -220,12 +307,18 @@ impl FeatureSet {
         piece_role: Role,
         piece_color: Color,
         perspective: Color,
+        refresh_mask: u64,
         add_feats: &mut Vec<u16>,
         rem_feats: &mut Vec<u16>,
     ) {
         let mut offset = 0;
 
-        for block in &self.blocks {
+        for (i, block) in self.blocks.iter().enumerate() {
+            if refresh_mask & (1 << i) != 0 {
+                offset += block.size();
+                continue;
+            }
+
             block.features_on_remove(
                 board,
                 piece_square,
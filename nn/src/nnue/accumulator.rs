@@ -1,6 +1,6 @@
 use super::{model::NnueModel, tensor::Tensor};
-use shakmaty::{Chess, Color, Move, Position};
-use std::{cell::RefCell, rc::Rc};
+use shakmaty::{Board, Chess, Color, Move, Position};
+use std::{cell::RefCell, sync::Arc};
 
 thread_local! {
     // buffers for storing temporary feature indexes
@@ -10,20 +10,64 @@ thread_local! {
     static INDEX_BUFFER4: RefCell<Vec<u16>> = RefCell::new(Vec::with_capacity(128));
 }
 
+/// Accumulator of a refresh bucket, with the rows that were active when it was last refreshed
+struct RefreshCacheEntry {
+    accumulation: Tensor<i16>,
+    /// Sorted and deduplicated, empty if the entry was never used
+    active_rows: Vec<u16>,
+}
+
+/// Cache of accumulators for refreshes ("Finny tables"), one per refresh bucket and perspective.
+/// Positions in the same bucket (e.g. same king square) share most features, so a refresh
+/// only applies the difference between the cached rows and the new ones instead of summing every row.
+///
+/// Updates only refresh from the cache when every block of the feature set requires a refresh
+/// (see `FeatureSet::requires_full_refresh`), otherwise `changed_features` recomputes the refreshed blocks.
+/// Entries are allocated the first time their bucket is refreshed, so sets that rarely use the cache
+/// (e.g. `all+hka`) do not pay for the accumulators of every bucket
+pub struct RefreshCache {
+    num_l1: usize,
+    // indexed by perspective (Color as usize), then by refresh bucket
+    entries: [Vec<Option<RefreshCacheEntry>>; 2],
+}
+
+impl RefreshCache {
+    /// Creates an empty cache for the given NNUE model
+    pub fn new(nnue_model: &NnueModel) -> Self {
+        let num_l1 = nnue_model.get_num_features();
+        let num_buckets = nnue_model.get_feature_set().num_refresh_buckets();
+
+        RefreshCache {
+            num_l1,
+            entries: std::array::from_fn(|_| (0..num_buckets).map(|_| None).collect()),
+        }
+    }
+
+    /// Forget every cached accumulator
+    pub fn clear(&mut self) {
+        // keep the allocated accumulators, they are overwritten on the next refresh
+        for entries in &mut self.entries {
+            for entry in entries.iter_mut().flatten() {
+                entry.active_rows.clear();
+            }
+        }
+    }
+}
+
 /// Accumulator for the first layer of the neural network. It tracks the features of both perspectives
 pub struct NnueAccumulator {
     // indexed by perspective (Color as usize)
     accumulation: [Tensor<i16>; 2],
     features: [Vec<i8>; 2],
 
-    nnue_model: Rc<RefCell<NnueModel>>,
+    nnue_model: Arc<NnueModel>,
 }
 
 impl NnueAccumulator {
     /// Creates an accumulator for the given NNUE model
-    pub fn new(nnue_model: Rc<RefCell<NnueModel>>) -> Self {
-        let num_l1 = nnue_model.borrow().get_num_features();
-        let num_features = nnue_model.borrow().get_feature_set().num_features() as usize;
+    pub fn new(nnue_model: Arc<NnueModel>) -> Self {
+        let num_l1 = nnue_model.get_num_features();
+        let num_features = nnue_model.get_feature_set().num_features() as usize;
 
         NnueAccumulator {
             nnue_model,
@@ -32,17 +76,33 @@ impl NnueAccumulator {
         }
     }
 
-    /// Returns the forward pass result for the given perspective, based on the current accumulator state
-    pub fn forward(&self, perspective: Color) -> i32 {
-        self.nnue_model.borrow().forward(
+    /// Returns the forward pass result for the given perspective, based on the current accumulator state.
+    /// The board of the current position selects the output bucket
+    pub fn forward(&self, board: &Board, perspective: Color) -> i32 {
+        self.nnue_model.forward(
             &self.accumulation[perspective as usize],
             &self.accumulation[perspective.other() as usize],
+            self.nnue_model.output_bucket(board),
         )
     }
 
     /// Throw away the current accumulator state for the given perspective and refresh it based on the given position
     pub fn refresh(&mut self, pos: &Chess, perspective: Color) {
-        let nnue_model = self.nnue_model.borrow();
+        self.refresh_with(pos, perspective, None);
+    }
+
+    /// Same as `refresh`, but starting from the cached accumulator of the position's refresh bucket
+    pub fn refresh_with_cache(
+        &mut self,
+        pos: &Chess,
+        perspective: Color,
+        cache: &mut RefreshCache,
+    ) {
+        self.refresh_with(pos, perspective, Some(cache));
+    }
+
+    fn refresh_with(&mut self, pos: &Chess, perspective: Color, cache: Option<&mut RefreshCache>) {
+        let nnue_model = &self.nnue_model;
         let feature_set = nnue_model.get_feature_set();
 
         let mut features = INDEX_BUFFER1.take();
@@ -58,17 +118,101 @@ impl NnueAccumulator {
             counts[f as usize] += 1;
         }
 
-        // refresh the accumulator
         features.sort_unstable();
         features.dedup(); // do not add rows twice!
-        nnue_model.refresh_accumulator(&self.accumulation[perspective as usize], &features);
+
+        let accumulation = &mut self.accumulation[perspective as usize];
+
+        let Some(cache) = cache else {
+            // refresh the accumulator from scratch
+            nnue_model.refresh_accumulator(accumulation, &features);
+            INDEX_BUFFER1.set(features);
+            return;
+        };
+
+        let bucket = feature_set.refresh_bucket(pos.board(), perspective);
+        let num_l1 = cache.num_l1;
+        let entry =
+            cache.entries[perspective as usize][bucket].get_or_insert_with(|| RefreshCacheEntry {
+                accumulation: Tensor::zeros(num_l1),
+                active_rows: Vec::new(),
+            });
+
+        if entry.active_rows.is_empty() {
+            nnue_model.refresh_accumulator(&mut entry.accumulation, &features);
+        } else {
+            let mut added_rows = INDEX_BUFFER3.take();
+            let mut removed_rows = INDEX_BUFFER4.take();
+
+            added_rows.clear();
+            removed_rows.clear();
+
+            // both lists are sorted, so the difference can be computed merging them
+            let (mut i, mut j) = (0, 0);
+            while i < features.len() || j < entry.active_rows.len() {
+                match (features.get(i), entry.active_rows.get(j)) {
+                    (Some(new), Some(old)) if new == old => {
+                        i += 1;
+                        j += 1;
+                    }
+                    (Some(&new), Some(&old)) if new < old => {
+                        added_rows.push(new);
+                        i += 1;
+                    }
+                    (Some(&new), None) => {
+                        added_rows.push(new);
+                        i += 1;
+                    }
+                    (_, Some(&old)) => {
+                        removed_rows.push(old);
+                        j += 1;
+                    }
+                    (None, None) => unreachable!(),
+                }
+            }
+
+            nnue_model.update_accumulator(&mut entry.accumulation, &added_rows, &removed_rows);
+
+            INDEX_BUFFER3.set(added_rows);
+            INDEX_BUFFER4.set(removed_rows);
+        }
+
+        accumulation
+            .as_mut_slice()
+            .copy_from_slice(entry.accumulation.as_slice());
+
+        // keep the rows for the next refresh, and reuse the old ones as buffer
+        std::mem::swap(&mut entry.active_rows, &mut features);
+        INDEX_BUFFER1.set(features);
     }
 
     /// Update the accumulator state based on the given move, for the given position and perspective
     pub fn update(&mut self, pos: &Chess, mov: &Move, perspective: Color) {
+        self.update_with(pos, mov, perspective, None);
+    }
+
+    /// Same as `update`, but refreshes (if needed) starting from the cached accumulator of the refresh bucket
+    pub fn update_with_cache(
+        &mut self,
+        pos: &Chess,
+        mov: &Move,
+        perspective: Color,
+        cache: &mut RefreshCache,
+    ) {
+        self.update_with(pos, mov, perspective, Some(cache));
+    }
+
+    fn update_with(
+        &mut self,
+        pos: &Chess,
+        mov: &Move,
+        perspective: Color,
+        cache: Option<&mut RefreshCache>,
+    ) {
         let board = pos.board();
 
-        if self.nnue_model.borrow().get_feature_set().requires_refresh(
+        // if only some blocks require a refresh, changed_features recomputes just those
+        if self.nnue_model.get_feature_set().requires_full_refresh(
             board,
             mov,
             pos.turn(),
@@ -76,12 +220,12 @@ impl NnueAccumulator {
         ) {
             let mut next_pos = pos.clone();
             next_pos.play_unchecked(mov);
-            self.refresh(&next_pos, perspective);
+            self.refresh_with(&next_pos, perspective, cache);
 
             return;
         }
 
-        let nnue_model = self.nnue_model.borrow();
+        let nnue_model = &self.nnue_model;
         let feature_set = nnue_model.get_feature_set();
         let counts = &mut self.features[perspective as usize];
 
@@ -158,7 +302,7 @@ impl NnueAccumulator {
 
         // do the math
         nnue_model.update_accumulator(
-            &self.accumulation[perspective as usize],
+            &mut self.accumulation[perspective as usize],
             &added_rows,
             &removed_rows,
         );
@@ -180,13 +324,14 @@ impl NnueAccumulator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nnue::model::test_utils;
     use shakmaty::uci::UciMove;
 
     #[test]
     fn test_update_refresh() {
         let model =
             NnueModel::from_memory(&include_bytes!("../../../models/best.nn").to_vec()).unwrap();
-        let mut acc = NnueAccumulator::new(Rc::new(RefCell::new(model)));
+        let mut acc = NnueAccumulator::new(Arc::new(model));
 
         let mut pos = Chess::default();
         let line = vec![
@@ -209,13 +354,13 @@ mod tests {
                 acc.update(&pos, &mov, persp);
 
                 // check score after update
-                let update_score = acc.forward(persp);
+                let update_score = acc.forward(next_pos.board(), persp);
 
                 // refresh the accumulator with the moved position
                 acc.refresh(&next_pos, persp);
 
                 // check score after refresh
-                let refresh_score = acc.forward(persp);
+                let refresh_score = acc.forward(next_pos.board(), persp);
 
                 assert_eq!(update_score, refresh_score);
             }
@@ -223,4 +368,55 @@ mod tests {
             pos = next_pos;
         }
     }
+
+    /// Updating with the cache must give the same accumulator as refreshing from scratch,
+    /// the kings cross refresh buckets and the E/D file boundary (mirroring) along the line
+    #[test]
+    fn test_refresh_cache() {
+        let (file, _) = test_utils::random_network("hka8", 8 * 64 * 11, 16, &[vec![8, 1]]);
+        let model = Arc::new(NnueModel::from_memory(&file).unwrap());
+        let mut cache = RefreshCache::new(&model);
+        let mut acc_cached = NnueAccumulator::new(model.clone());
+        let mut acc = NnueAccumulator::new(model);
+
+        let mut pos = Chess::default();
+        let line = vec![
+            "e2e4", "e7e5", "d2d4", "e5d4", "e1e2", "e8e7", "e2d3", "e7d6", "d3d4", "d6c6", "c2c4",
+            "c6b6", "d4e3", "b6c6", "e3e2", "c6d6", "e2d2", "d6e7",
+        ];
+
+        for &persp in &[Color::White, Color::Black] {
+            acc_cached.refresh_with_cache(&pos, persp, &mut cache);
+        }
+
+        for (i, mov) in line.iter().enumerate() {
+            let mov = UciMove::from_ascii(mov.as_bytes())
+                .unwrap()
+                .to_move(&pos)
+                .unwrap();
+            let next_pos = pos.clone().play(&mov).unwrap();
+
+            for &persp in &[Color::White, Color::Black] {
+                acc_cached.update_with_cache(&pos, &mov, persp, &mut cache);
+                acc.refresh(&next_pos, persp);
+
+                assert_eq!(
+                    acc_cached.accumulation[persp as usize].as_slice(),
+                    acc.accumulation[persp as usize].as_slice(),
+                    "ply {}",
+                    i + 1
+                );
+                assert_eq!(
+                    acc_cached.features[persp as usize],
+                    acc.features[persp as usize]
+                );
+            }
+
+            if i == line.len() / 2 {
+                cache.clear();
+            }
+
+            pos = next_pos;
+        }
+    }
 }
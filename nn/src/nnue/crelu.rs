@@ -1,10 +1,108 @@
+use super::simd::SimdBackend;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
 /// Clipped ReLU activation function from i16 elements
 /// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#int16---int8
+pub unsafe fn crelu_16(backend: SimdBackend, size: usize, input: *const i16, output: *mut i8) {
+    match backend {
+        // AVX-512 packs within 128-bit lanes too, the AVX2 version is used since activations are small
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx2 | SimdBackend::Avx512Vnni if size % 32 == 0 => {
+            crelu_16_avx2(size, input, output)
+        }
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Sse41 | SimdBackend::Avx2 | SimdBackend::Avx512Vnni if size % 16 == 0 => {
+            crelu_16_sse41(size, input, output)
+        }
+        _ => crelu_16_scalar(size, input, output),
+    }
+}
+
+/// Clipped ReLU activation function from i32 elements
+/// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#int32---int8
+pub unsafe fn crelu_32(backend: SimdBackend, size: usize, input: *const i32, output: *mut i8) {
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx2 | SimdBackend::Avx512Vnni if size % 32 == 0 => {
+            crelu_32_avx2(size, input, output)
+        }
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Sse41 | SimdBackend::Avx2 | SimdBackend::Avx512Vnni if size % 16 == 0 => {
+            crelu_32_sse41(size, input, output)
+        }
+        _ => crelu_32_scalar(size, input, output),
+    }
+}
+
+/// Reference implementation of `crelu_16`, the SIMD versions saturate to [0, 127] the same way
+unsafe fn crelu_16_scalar(size: usize, input: *const i16, output: *mut i8) {
+    for i in 0..size {
+        *output.add(i) = (*input.add(i)).clamp(0, 127) as i8;
+    }
+}
+
+/// Reference implementation of `crelu_32`
+unsafe fn crelu_32_scalar(size: usize, input: *const i32, output: *mut i8) {
+    for i in 0..size {
+        *output.add(i) = (*input.add(i)).clamp(0, 127) as i8;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn crelu_16_sse41(size: usize, input: *const i16, output: *mut i8) {
+    const IN_REG_WIDTH: usize = 128 / 16;
+    const OUT_REG_WIDTH: usize = 128 / 8;
+
+    debug_assert!(size % OUT_REG_WIDTH == 0); // processing 16 elements at a time
+
+    let num_out_chunks = size / OUT_REG_WIDTH;
+
+    let zero = _mm_setzero_si128();
+
+    for i in 0..num_out_chunks {
+        let in0 = _mm_load_si128(input.add((i * 2 + 0) * IN_REG_WIDTH) as *const __m128i);
+        let in1 = _mm_load_si128(input.add((i * 2 + 1) * IN_REG_WIDTH) as *const __m128i);
+
+        // there is a single lane, so the packed elements are already in order
+        let result = _mm_max_epi8(_mm_packs_epi16(in0, in1), zero);
+
+        _mm_store_si128(output.add(i * OUT_REG_WIDTH) as *mut __m128i, result);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn crelu_32_sse41(size: usize, input: *const i32, output: *mut i8) {
+    const IN_REG_WIDTH: usize = 128 / 32;
+    const OUT_REG_WIDTH: usize = 128 / 8;
+
+    debug_assert!(size % OUT_REG_WIDTH == 0); // processing 16 elements at a time
+
+    let num_out_chunks = size / OUT_REG_WIDTH;
+
+    let zero = _mm_setzero_si128();
+
+    for i in 0..num_out_chunks {
+        let in0 = _mm_packs_epi32(
+            _mm_load_si128(input.add((i * 4 + 0) * IN_REG_WIDTH) as *const __m128i),
+            _mm_load_si128(input.add((i * 4 + 1) * IN_REG_WIDTH) as *const __m128i),
+        );
+        let in1 = _mm_packs_epi32(
+            _mm_load_si128(input.add((i * 4 + 2) * IN_REG_WIDTH) as *const __m128i),
+            _mm_load_si128(input.add((i * 4 + 3) * IN_REG_WIDTH) as *const __m128i),
+        );
+
+        let result = _mm_max_epi8(_mm_packs_epi16(in0, in1), zero);
+
+        _mm_store_si128(output.add(i * OUT_REG_WIDTH) as *mut __m128i, result);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
 #[target_feature(enable = "avx2")]
-pub unsafe fn crelu_16(size: usize, input: *const i16, output: *mut i8) {
+unsafe fn crelu_16_avx2(size: usize, input: *const i16, output: *mut i8) {
     const IN_REG_WIDTH: usize = 256 / 16;
     const OUT_REG_WIDTH: usize = 256 / 8;
 
@@ -26,10 +124,9 @@ pub unsafe fn crelu_16(size: usize, input: *const i16, output: *mut i8) {
     }
 }
 
-/// Clipped ReLU activation function from i32 elements
-/// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#int32---int8
+#[cfg(target_arch = "x86_64")]
 #[target_feature(enable = "avx2")]
-pub unsafe fn crelu_32(size: usize, input: *const i32, output: *mut i8) {
+unsafe fn crelu_32_avx2(size: usize, input: *const i32, output: *mut i8) {
     const IN_REG_WIDTH: usize = 256 / 32;
     const OUT_REG_WIDTH: usize = 256 / 8;
 
@@ -58,3 +155,51 @@ pub unsafe fn crelu_32(size: usize, input: *const i32, output: *mut i8) {
         _mm256_store_si256(output.add(i * OUT_REG_WIDTH) as *mut __m256i, result);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::tensor::Tensor;
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Every supported backend must give exactly the same output as the scalar version
+    #[test]
+    fn test_backends_match_scalar() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..200 {
+            let size = 16 * rng.gen_range(1..=64);
+
+            let mut input_16 = Tensor::<i16>::zeros(size);
+            let mut input_32 = Tensor::<i32>::zeros(size);
+            input_16
+                .as_mut_slice()
+                .iter_mut()
+                .for_each(|x| *x = rng.gen());
+            // mostly values around the clipping range, some outside the i16 range
+            input_32
+                .as_mut_slice()
+                .iter_mut()
+                .for_each(|x| *x = rng.gen_range(-300..300) * rng.gen_range(1..=200));
+
+            let mut expected_16 = Tensor::<i8>::zeros(size);
+            let mut expected_32 = Tensor::<i8>::zeros(size);
+            unsafe {
+                crelu_16_scalar(size, input_16.as_ptr(), expected_16.as_mut_ptr());
+                crelu_32_scalar(size, input_32.as_ptr(), expected_32.as_mut_ptr());
+            }
+
+            for backend in SimdBackend::ALL.into_iter().filter(|b| b.is_supported()) {
+                let mut output_16 = Tensor::<i8>::zeros(size);
+                let mut output_32 = Tensor::<i8>::zeros(size);
+                unsafe {
+                    crelu_16(backend, size, input_16.as_ptr(), output_16.as_mut_ptr());
+                    crelu_32(backend, size, input_32.as_ptr(), output_32.as_mut_ptr());
+                }
+
+                assert_eq!(output_16.as_slice(), expected_16.as_slice(), "{}", backend);
+                assert_eq!(output_32.as_slice(), expected_32.as_slice(), "{}", backend);
+            }
+        }
+    }
+}
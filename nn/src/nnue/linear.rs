@@ -1,14 +1,15 @@
+use super::simd::SimdBackend;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
 // These are constants because `_mm_srai_epi32` requires a constant shift value
-const LOG2_HIDDEN_WEIGHT_SCALE: i32 = 6;
-const LOG2_OUTPUT_WEIGHT_SCALE: i32 = 4;
+pub(super) const LOG2_HIDDEN_WEIGHT_SCALE: i32 = 6;
+pub(super) const LOG2_OUTPUT_WEIGHT_SCALE: i32 = 4;
 
 /// Quantized linear layer with 8-bit weights and 32-bits bias
 /// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#linear-layer-4
-#[target_feature(enable = "avx2")]
 pub unsafe fn linear(
+    backend: SimdBackend,
     num_inputs: usize,
     num_outputs: usize,
     input: *const i8,
@@ -29,6 +30,257 @@ pub unsafe fn linear(
         return;
     }
 
+    // the inputs are processed in chunks of the register width and the outputs 4 at a time
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx512Vnni if num_inputs % 64 == 0 && num_outputs % 4 == 0 => {
+            linear_avx512(num_inputs, num_outputs, input, weight, bias, output)
+        }
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx2 | SimdBackend::Avx512Vnni
+            if num_inputs % 32 == 0 && num_outputs % 4 == 0 =>
+        {
+            linear_avx2(num_inputs, num_outputs, input, weight, bias, output)
+        }
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Sse41 | SimdBackend::Avx2 | SimdBackend::Avx512Vnni
+            if num_inputs % 16 == 0 && num_outputs % 4 == 0 =>
+        {
+            linear_sse41(num_inputs, num_outputs, input, weight, bias, output)
+        }
+        _ => linear_scalar(num_inputs, num_outputs, input, weight, bias, output),
+    }
+}
+
+/// Refresh accumulator
+/// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#feature-transformer-2
+pub unsafe fn linear_partial_refresh(
+    backend: SimdBackend,
+    num_inputs: usize,
+    num_outputs: usize,
+    active_rows: &[u16],
+    weight: *const i16,
+    bias: *const i16,
+    output: *mut i16,
+) {
+    // the outputs are processed in passes of the register width times the number of registers
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx512Vnni if num_outputs % 256 == 0 => linear_partial_refresh_avx512(
+            num_inputs,
+            num_outputs,
+            active_rows,
+            weight,
+            bias,
+            output,
+        ),
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx2 | SimdBackend::Avx512Vnni if num_outputs % 256 == 0 => {
+            linear_partial_refresh_avx2(num_inputs, num_outputs, active_rows, weight, bias, output)
+        }
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Sse41 | SimdBackend::Avx2 | SimdBackend::Avx512Vnni
+            if num_outputs % 128 == 0 =>
+        {
+            linear_partial_refresh_sse41(num_inputs, num_outputs, active_rows, weight, bias, output)
+        }
+        _ => linear_partial_refresh_scalar(num_outputs, active_rows, weight, bias, output),
+    }
+}
+
+/// Update accumulator
+/// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#feature-transformer-2
+pub unsafe fn linear_partial_update(
+    backend: SimdBackend,
+    num_inputs: usize,
+    num_outputs: usize,
+    added_rows: &[u16],
+    removed_rows: &[u16],
+    weight: *const i16,
+    inout: *const i16,
+) {
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx512Vnni if num_outputs % 256 == 0 => linear_partial_update_avx512(
+            num_inputs,
+            num_outputs,
+            added_rows,
+            removed_rows,
+            weight,
+            inout,
+        ),
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Avx2 | SimdBackend::Avx512Vnni if num_outputs % 256 == 0 => {
+            linear_partial_update_avx2(
+                num_inputs,
+                num_outputs,
+                added_rows,
+                removed_rows,
+                weight,
+                inout,
+            )
+        }
+        #[cfg(target_arch = "x86_64")]
+        SimdBackend::Sse41 | SimdBackend::Avx2 | SimdBackend::Avx512Vnni
+            if num_outputs % 128 == 0 =>
+        {
+            linear_partial_update_sse41(
+                num_inputs,
+                num_outputs,
+                added_rows,
+                removed_rows,
+                weight,
+                inout,
+            )
+        }
+        _ => linear_partial_update_scalar(num_outputs, added_rows, removed_rows, weight, inout),
+    }
+}
+
+/// Reference implementation of `linear` for hidden layers
+/// Integer operations wrap around like the SIMD instructions, so the results are the same
+unsafe fn linear_scalar(
+    num_inputs: usize,
+    num_outputs: usize,
+    input: *const i8,
+    weight: *const i8,
+    bias: *const i32,
+    output: *mut i32,
+) {
+    for o in 0..num_outputs {
+        let mut outval = *bias.add(o);
+        for i in 0..num_inputs {
+            // the inputs are unsigned (they come from the clipped ReLU)
+            let val1 = *input.add(i) as u8 as i32;
+            let val2 = *weight.add(o * num_inputs + i) as i32;
+            outval = outval.wrapping_add(val1 * val2);
+        }
+        // account for weight scaling
+        *output.add(o) = outval >> LOG2_HIDDEN_WEIGHT_SCALE;
+    }
+}
+
+/// Reference implementation of `linear_partial_refresh`
+unsafe fn linear_partial_refresh_scalar(
+    num_outputs: usize,
+    active_rows: &[u16],
+    weight: *const i16,
+    bias: *const i16,
+    output: *mut i16,
+) {
+    std::ptr::copy_nonoverlapping(bias, output, num_outputs);
+
+    for &a in active_rows {
+        for o in 0..num_outputs {
+            let value = *weight.add((a as usize) * num_outputs + o);
+            *output.add(o) = (*output.add(o)).wrapping_add(value);
+        }
+    }
+}
+
+/// Reference implementation of `linear_partial_update`
+unsafe fn linear_partial_update_scalar(
+    num_outputs: usize,
+    added_rows: &[u16],
+    removed_rows: &[u16],
+    weight: *const i16,
+    inout: *const i16,
+) {
+    let inout = inout as *mut i16;
+
+    for &r in removed_rows {
+        for o in 0..num_outputs {
+            let value = *weight.add((r as usize) * num_outputs + o);
+            *inout.add(o) = (*inout.add(o)).wrapping_sub(value);
+        }
+    }
+    for &a in added_rows {
+        for o in 0..num_outputs {
+            let value = *weight.add((a as usize) * num_outputs + o);
+            *inout.add(o) = (*inout.add(o)).wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn linear_sse41(
+    num_inputs: usize,
+    num_outputs: usize,
+    input: *const i8,
+    weight: *const i8,
+    bias: *const i32,
+    output: *mut i32,
+) {
+    const REGISTER_WIDTH: usize = 128 / 8;
+
+    debug_assert!(num_inputs % REGISTER_WIDTH == 0); // processing 16 elements at a time
+    debug_assert!(num_outputs % 4 == 0); // processing 4 elements at a time
+
+    let num_in_chunks: usize = num_inputs / REGISTER_WIDTH;
+    let num_out_chunks: usize = num_outputs / 4;
+
+    for i in 0..num_out_chunks {
+        let offset0 = (i * 4 + 0) * num_inputs;
+        let offset1 = (i * 4 + 1) * num_inputs;
+        let offset2 = (i * 4 + 2) * num_inputs;
+        let offset3 = (i * 4 + 3) * num_inputs;
+
+        let mut sum0 = _mm_setzero_si128();
+        let mut sum1 = _mm_setzero_si128();
+        let mut sum2 = _mm_setzero_si128();
+        let mut sum3 = _mm_setzero_si128();
+
+        for j in 0..num_in_chunks {
+            let inp = _mm_load_si128(input.add(j * REGISTER_WIDTH) as *const __m128i);
+
+            let w0 = _mm_load_si128(weight.add(offset0 + j * REGISTER_WIDTH) as *const __m128i);
+            let w1 = _mm_load_si128(weight.add(offset1 + j * REGISTER_WIDTH) as *const __m128i);
+            let w2 = _mm_load_si128(weight.add(offset2 + j * REGISTER_WIDTH) as *const __m128i);
+            let w3 = _mm_load_si128(weight.add(offset3 + j * REGISTER_WIDTH) as *const __m128i);
+
+            m128_add_dpbusd_epi32(&mut sum0, inp, w0);
+            m128_add_dpbusd_epi32(&mut sum1, inp, w1);
+            m128_add_dpbusd_epi32(&mut sum2, inp, w2);
+            m128_add_dpbusd_epi32(&mut sum3, inp, w3);
+        }
+
+        let bias = _mm_load_si128(bias.add(i * 4) as *const __m128i);
+
+        // m128_haddx4
+        sum0 = _mm_hadd_epi32(sum0, sum1);
+        sum2 = _mm_hadd_epi32(sum2, sum3);
+        sum0 = _mm_hadd_epi32(sum0, sum2);
+
+        let mut outval = _mm_add_epi32(sum0, bias);
+
+        // account for weight scaling
+        outval = _mm_srai_epi32(outval, LOG2_HIDDEN_WEIGHT_SCALE);
+
+        _mm_store_si128(output.add(i * 4) as *mut __m128i, outval);
+    }
+}
+
+/// 128-bit version of `m256_add_dpbusd_epi32`
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn m128_add_dpbusd_epi32(acc: &mut __m128i, a: __m128i, b: __m128i) {
+    let mut product0 = _mm_maddubs_epi16(a, b);
+    let one = _mm_set1_epi16(1);
+    product0 = _mm_madd_epi16(product0, one);
+    *acc = _mm_add_epi32(*acc, product0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn linear_avx2(
+    num_inputs: usize,
+    num_outputs: usize,
+    input: *const i8,
+    weight: *const i8,
+    bias: *const i32,
+    output: *mut i32,
+) {
     const REGISTER_WIDTH: usize = 256 / 8;
 
     debug_assert!(num_inputs % REGISTER_WIDTH == 0); // processing 32 elements at a time
@@ -85,26 +337,131 @@ pub unsafe fn linear(
 }
 
 /// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#m256_add_dpbusd_epi32
+/// The inputs come from the clipped ReLU (at most 127), so the 16-bit products of `maddubs` can't saturate
+#[cfg(target_arch = "x86_64")]
 #[inline(always)]
 unsafe fn m256_add_dpbusd_epi32(acc: &mut __m256i, a: __m256i, b: __m256i) {
-    #[cfg(target_feature = "avx512vnni,avx512vl")]
-    {
-        *acc = _mm256_dpbusd_epi32(*acc, a, b);
+    let mut product0 = _mm256_maddubs_epi16(a, b);
+    let one = _mm256_set1_epi16(1);
+    product0 = _mm256_madd_epi16(product0, one);
+    *acc = _mm256_add_epi32(*acc, product0);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f,avx512bw,avx512vnni")]
+unsafe fn linear_avx512(
+    num_inputs: usize,
+    num_outputs: usize,
+    input: *const i8,
+    weight: *const i8,
+    bias: *const i32,
+    output: *mut i32,
+) {
+    const REGISTER_WIDTH: usize = 512 / 8;
+
+    debug_assert!(num_inputs % REGISTER_WIDTH == 0); // processing 64 elements at a time
+    debug_assert!(num_outputs % 4 == 0); // processing 4 elements at a time
+
+    let num_in_chunks: usize = num_inputs / REGISTER_WIDTH;
+    let num_out_chunks: usize = num_outputs / 4;
+
+    for i in 0..num_out_chunks {
+        let offset0 = (i * 4 + 0) * num_inputs;
+        let offset1 = (i * 4 + 1) * num_inputs;
+        let offset2 = (i * 4 + 2) * num_inputs;
+        let offset3 = (i * 4 + 3) * num_inputs;
+
+        let mut sum0 = _mm512_setzero_si512();
+        let mut sum1 = _mm512_setzero_si512();
+        let mut sum2 = _mm512_setzero_si512();
+        let mut sum3 = _mm512_setzero_si512();
+
+        for j in 0..num_in_chunks {
+            let inp = _mm512_load_si512(input.add(j * REGISTER_WIDTH) as *const _);
+
+            let w0 = _mm512_load_si512(weight.add(offset0 + j * REGISTER_WIDTH) as *const _);
+            let w1 = _mm512_load_si512(weight.add(offset1 + j * REGISTER_WIDTH) as *const _);
+            let w2 = _mm512_load_si512(weight.add(offset2 + j * REGISTER_WIDTH) as *const _);
+            let w3 = _mm512_load_si512(weight.add(offset3 + j * REGISTER_WIDTH) as *const _);
+
+            // u8 x i8 dot products of 4 elements, accumulated in i32 without intermediate saturation
+            sum0 = _mm512_dpbusd_epi32(sum0, inp, w0);
+            sum1 = _mm512_dpbusd_epi32(sum1, inp, w1);
+            sum2 = _mm512_dpbusd_epi32(sum2, inp, w2);
+            sum3 = _mm512_dpbusd_epi32(sum3, inp, w3);
+        }
+
+        let sums = _mm_set_epi32(
+            _mm512_reduce_add_epi32(sum3),
+            _mm512_reduce_add_epi32(sum2),
+            _mm512_reduce_add_epi32(sum1),
+            _mm512_reduce_add_epi32(sum0),
+        );
+        let bias = _mm_load_si128(bias.add(i * 4) as *const __m128i);
+
+        // account for weight scaling
+        let outval = _mm_srai_epi32(_mm_add_epi32(sums, bias), LOG2_HIDDEN_WEIGHT_SCALE);
+
+        _mm_store_si128(output.add(i * 4) as *mut __m128i, outval);
     }
+}
 
-    #[cfg(not(target_feature = "avx512vnni,avx512vl"))]
-    {
-        let mut product0 = _mm256_maddubs_epi16(a, b);
-        let one = _mm256_set1_epi16(1);
-        product0 = _mm256_madd_epi16(product0, one);
-        *acc = _mm256_add_epi32(*acc, product0);
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn linear_partial_refresh_sse41(
+    num_inputs: usize,
+    num_outputs: usize,
+    active_rows: &[u16],
+    weight: *const i16,
+    bias: *const i16,
+    output: *mut i16,
+) {
+    const REGISTER_WIDTH: usize = 128 / 16;
+    const NUM_CHUNKS: usize = 16;
+
+    let num_passes = num_outputs / (REGISTER_WIDTH * NUM_CHUNKS);
+
+    debug_assert!(num_outputs % (REGISTER_WIDTH * NUM_CHUNKS) == 0); // must be multiple of 128
+    debug_assert!(num_inputs % REGISTER_WIDTH == 0); // processing 8 elements at a time
+
+    let mut regs: [__m128i; NUM_CHUNKS] = unsafe { std::mem::zeroed() };
+
+    // we have 16 registers, each with 8 i16 elements (128 bits each)
+    for p in 0..num_passes {
+        // offset for the current pass
+        let p_off = p * NUM_CHUNKS * REGISTER_WIDTH;
+
+        // init registers with bias
+        for i in 0..NUM_CHUNKS {
+            regs[i] = _mm_load_si128(bias.add(p_off + i * REGISTER_WIDTH) as *const __m128i);
+        }
+
+        // accumulate active rows
+        for &a in active_rows {
+            for i in 0..NUM_CHUNKS {
+                regs[i] = _mm_add_epi16(
+                    regs[i],
+                    _mm_load_si128(
+                        weight.add((a as usize) * num_outputs + p_off + i * REGISTER_WIDTH)
+                            as *const __m128i,
+                    ),
+                );
+            }
+        }
+
+        // copy to output
+        for i in 0..NUM_CHUNKS {
+            _mm_store_si128(
+                output.add(p_off + i * REGISTER_WIDTH) as *mut __m128i,
+                regs[i],
+            );
+        }
     }
 }
 
-/// Refresh accumulator
-/// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#feature-transformer-2
+#[cfg(target_arch = "x86_64")]
 #[target_feature(enable = "avx2")]
-pub unsafe fn linear_partial_refresh(
+unsafe fn linear_partial_refresh_avx2(
     num_inputs: usize,
     num_outputs: usize,
     active_rows: &[u16],
@@ -156,10 +513,124 @@ pub unsafe fn linear_partial_refresh(
     }
 }
 
-/// Update accumulator
-/// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md#feature-transformer-2
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f,avx512bw")]
+unsafe fn linear_partial_refresh_avx512(
+    _num_inputs: usize,
+    num_outputs: usize,
+    active_rows: &[u16],
+    weight: *const i16,
+    bias: *const i16,
+    output: *mut i16,
+) {
+    const REGISTER_WIDTH: usize = 512 / 16;
+    const NUM_CHUNKS: usize = 8;
+
+    let num_passes = num_outputs / (REGISTER_WIDTH * NUM_CHUNKS);
+
+    debug_assert!(num_outputs % (REGISTER_WIDTH * NUM_CHUNKS) == 0); // must be multiple of 256
+
+    let mut regs: [__m512i; NUM_CHUNKS] = unsafe { std::mem::zeroed() };
+
+    // we use 8 registers, each with 32 i16 elements (512 bits each)
+    for p in 0..num_passes {
+        // offset for the current pass
+        let p_off = p * NUM_CHUNKS * REGISTER_WIDTH;
+
+        // init registers with bias
+        for i in 0..NUM_CHUNKS {
+            regs[i] = _mm512_load_si512(bias.add(p_off + i * REGISTER_WIDTH) as *const _);
+        }
+
+        // accumulate active rows
+        for &a in active_rows {
+            for i in 0..NUM_CHUNKS {
+                regs[i] = _mm512_add_epi16(
+                    regs[i],
+                    _mm512_load_si512(
+                        weight.add((a as usize) * num_outputs + p_off + i * REGISTER_WIDTH)
+                            as *const _,
+                    ),
+                );
+            }
+        }
+
+        // copy to output
+        for i in 0..NUM_CHUNKS {
+            _mm512_store_si512(output.add(p_off + i * REGISTER_WIDTH) as *mut _, regs[i]);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn linear_partial_update_sse41(
+    num_inputs: usize,
+    num_outputs: usize,
+    added_rows: &[u16],
+    removed_rows: &[u16],
+    weight: *const i16,
+    inout: *const i16,
+) {
+    const REGISTER_WIDTH: usize = 128 / 16;
+    const NUM_CHUNKS: usize = 16;
+
+    let num_passes = num_outputs / (REGISTER_WIDTH * NUM_CHUNKS);
+
+    debug_assert!(num_outputs % (REGISTER_WIDTH * NUM_CHUNKS) == 0); // must be multiple of 128
+    debug_assert!(num_inputs % REGISTER_WIDTH == 0); // processing 8 elements at a time
+
+    let mut regs: [__m128i; NUM_CHUNKS] = unsafe { std::mem::zeroed() };
+
+    // we have 16 registers, each with 8 i16 elements (128 bits each)
+    for p in 0..num_passes {
+        // offset for the current pass
+        let p_off = p * NUM_CHUNKS * REGISTER_WIDTH;
+
+        // copy all existing values into the registers
+        for i in 0..NUM_CHUNKS {
+            regs[i] = _mm_load_si128(inout.add(p_off + i * REGISTER_WIDTH) as *const __m128i);
+        }
+
+        // subtract removed rows
+        for &r in removed_rows {
+            for i in 0..NUM_CHUNKS {
+                regs[i] = _mm_sub_epi16(
+                    regs[i],
+                    _mm_load_si128(
+                        weight.add((r as usize) * num_outputs + p_off + i * REGISTER_WIDTH)
+                            as *const __m128i,
+                    ),
+                );
+            }
+        }
+
+        // add added rows
+        for &a in added_rows {
+            for i in 0..NUM_CHUNKS {
+                regs[i] = _mm_add_epi16(
+                    regs[i],
+                    _mm_load_si128(
+                        weight.add((a as usize) * num_outputs + p_off + i * REGISTER_WIDTH)
+                            as *const __m128i,
+                    ),
+                );
+            }
+        }
+
+        // copy the result back
+        for i in 0..NUM_CHUNKS {
+            _mm_store_si128(
+                inout.add(p_off + i * REGISTER_WIDTH) as *mut __m128i,
+                regs[i],
+            );
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
 #[target_feature(enable = "avx2")]
-pub unsafe fn linear_partial_update(
+unsafe fn linear_partial_update_avx2(
     num_inputs: usize,
     num_outputs: usize,
     added_rows: &[u16],
@@ -223,3 +694,204 @@ pub unsafe fn linear_partial_update(
         }
     }
 }
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f,avx512bw")]
+unsafe fn linear_partial_update_avx512(
+    _num_inputs: usize,
+    num_outputs: usize,
+    added_rows: &[u16],
+    removed_rows: &[u16],
+    weight: *const i16,
+    inout: *const i16,
+) {
+    const REGISTER_WIDTH: usize = 512 / 16;
+    const NUM_CHUNKS: usize = 8;
+
+    let num_passes = num_outputs / (REGISTER_WIDTH * NUM_CHUNKS);
+
+    debug_assert!(num_outputs % (REGISTER_WIDTH * NUM_CHUNKS) == 0); // must be multiple of 256
+
+    let mut regs: [__m512i; NUM_CHUNKS] = unsafe { std::mem::zeroed() };
+
+    // we use 8 registers, each with 32 i16 elements (512 bits each)
+    for p in 0..num_passes {
+        // offset for the current pass
+        let p_off = p * NUM_CHUNKS * REGISTER_WIDTH;
+
+        // copy all existing values into the registers
+        for i in 0..NUM_CHUNKS {
+            regs[i] = _mm512_load_si512(inout.add(p_off + i * REGISTER_WIDTH) as *const _);
+        }
+
+        // subtract removed rows
+        for &r in removed_rows {
+            for i in 0..NUM_CHUNKS {
+                regs[i] = _mm512_sub_epi16(
+                    regs[i],
+                    _mm512_load_si512(
+                        weight.add((r as usize) * num_outputs + p_off + i * REGISTER_WIDTH)
+                            as *const _,
+                    ),
+                );
+            }
+        }
+
+        // add added rows
+        for &a in added_rows {
+            for i in 0..NUM_CHUNKS {
+                regs[i] = _mm512_add_epi16(
+                    regs[i],
+                    _mm512_load_si512(
+                        weight.add((a as usize) * num_outputs + p_off + i * REGISTER_WIDTH)
+                            as *const _,
+                    ),
+                );
+            }
+        }
+
+        // copy the result back
+        for i in 0..NUM_CHUNKS {
+            _mm512_store_si512(inout.add(p_off + i * REGISTER_WIDTH) as *mut _, regs[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tensor::Tensor;
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_tensor<T>(rng: &mut StdRng, len: usize) -> Tensor<T>
+    where
+        rand::distributions::Standard: rand::distributions::Distribution<T>,
+    {
+        let mut tensor = Tensor::zeros(len);
+        tensor
+            .as_mut_slice()
+            .iter_mut()
+            .for_each(|x| *x = rng.gen());
+        tensor
+    }
+
+    fn supported_backends() -> impl Iterator<Item = SimdBackend> {
+        SimdBackend::ALL.into_iter().filter(|b| b.is_supported())
+    }
+
+    /// Every supported backend must give exactly the same output as the scalar version
+    #[test]
+    fn test_linear_backends_match_scalar() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..200 {
+            let num_inputs = 16 * rng.gen_range(1..=64);
+            let num_outputs = 4 * rng.gen_range(1..=16);
+
+            // inputs come from the clipped ReLU
+            let mut input = Tensor::<i8>::zeros(num_inputs);
+            input
+                .as_mut_slice()
+                .iter_mut()
+                .for_each(|x| *x = rng.gen_range(0..=127));
+            let weight = random_tensor::<i8>(&mut rng, num_inputs * num_outputs);
+            let mut bias = Tensor::<i32>::zeros(num_outputs);
+            bias.as_mut_slice()
+                .iter_mut()
+                .for_each(|x| *x = rng.gen_range(-100_000..100_000));
+
+            let mut expected = Tensor::<i32>::zeros(num_outputs);
+            unsafe {
+                linear_scalar(
+                    num_inputs,
+                    num_outputs,
+                    input.as_ptr(),
+                    weight.as_ptr(),
+                    bias.as_ptr(),
+                    expected.as_mut_ptr(),
+                );
+            }
+
+            for backend in supported_backends() {
+                let mut output = Tensor::<i32>::zeros(num_outputs);
+                unsafe {
+                    linear(
+                        backend,
+                        num_inputs,
+                        num_outputs,
+                        input.as_ptr(),
+                        weight.as_ptr(),
+                        bias.as_ptr(),
+                        output.as_mut_ptr(),
+                    );
+                }
+
+                assert_eq!(output.as_slice(), expected.as_slice(), "{}", backend);
+            }
+        }
+    }
+
+    /// Same as above for the accumulator refresh and update, including overflows
+    #[test]
+    fn test_partial_backends_match_scalar() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..50 {
+            let num_inputs = 16 * rng.gen_range(1..=8);
+            let num_outputs = 128 * rng.gen_range(1..=8);
+
+            let weight = random_tensor::<i16>(&mut rng, num_inputs * num_outputs);
+            let bias = random_tensor::<i16>(&mut rng, num_outputs);
+            let mut rows = || {
+                (0..rng.gen_range(0..32))
+                    .map(|_| rng.gen_range(0..num_inputs as u16))
+                    .collect::<Vec<_>>()
+            };
+            let (active, added, removed) = (rows(), rows(), rows());
+
+            let mut expected = Tensor::<i16>::zeros(num_outputs);
+            unsafe {
+                linear_partial_refresh_scalar(
+                    num_outputs,
+                    &active,
+                    weight.as_ptr(),
+                    bias.as_ptr(),
+                    expected.as_mut_ptr(),
+                );
+                linear_partial_update_scalar(
+                    num_outputs,
+                    &added,
+                    &removed,
+                    weight.as_ptr(),
+                    expected.as_ptr(),
+                );
+            }
+
+            for backend in supported_backends() {
+                let mut output = Tensor::<i16>::zeros(num_outputs);
+                unsafe {
+                    linear_partial_refresh(
+                        backend,
+                        num_inputs,
+                        num_outputs,
+                        &active,
+                        weight.as_ptr(),
+                        bias.as_ptr(),
+                        output.as_mut_ptr(),
+                    );
+                    linear_partial_update(
+                        backend,
+                        num_inputs,
+                        num_outputs,
+                        &added,
+                        &removed,
+                        weight.as_ptr(),
+                        output.as_ptr(),
+                    );
+                }
+
+                assert_eq!(output.as_slice(), expected.as_slice(), "{}", backend);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 mod crelu;
 mod linear;
+pub mod simd;
 mod tensor;
 
 pub mod model;
@@ -1,12 +1,240 @@
 use super::crelu::{crelu_16, crelu_32};
-use super::linear::{linear, linear_partial_refresh, linear_partial_update};
+use super::linear::{
+    linear, linear_partial_refresh, linear_partial_update, LOG2_HIDDEN_WEIGHT_SCALE,
+    LOG2_OUTPUT_WEIGHT_SCALE,
+};
+use super::simd::SimdBackend;
 use super::tensor::Tensor;
-use crate::feature_set::build::build_feature_set;
+use crate::feature_set::build::try_build_feature_set;
 use crate::feature_set::FeatureSet;
 use byteorder::{LittleEndian, ReadBytesExt};
+use shakmaty::Board;
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::{self, BufRead, Cursor, Read};
 
+thread_local! {
+    // scratch buffers for the forward pass
+    // they live outside the model so it can be shared between threads without locking
+    static FORWARD_BUFFERS: RefCell<ForwardBuffers> = RefCell::new(ForwardBuffers {
+        activations: Tensor::zeros(1024),
+        outputs: Tensor::zeros(32),
+    });
+}
+
+/// Buffers used between layers in the forward pass
+struct ForwardBuffers {
+    // output of the activation functions, which is the input of the next layer
+    activations: Tensor<i8>,
+    // output of a linear layer, before applying the activation
+    outputs: Tensor<i32>,
+}
+
+impl ForwardBuffers {
+    /// Make sure the buffers can hold the given number of elements
+    fn reserve(&mut self, num_activations: usize, num_outputs: usize) {
+        if self.activations.len() < num_activations {
+            self.activations = Tensor::zeros(num_activations);
+        }
+        if self.outputs.len() < num_outputs {
+            self.outputs = Tensor::zeros(num_outputs);
+        }
+    }
+}
+
+/// Magic bytes at the start of versioned .nn files
+const MAGIC: &[u8; 4] = b"NNUE";
+
+/// Version of the .nn format written by `scripts/lib/serialize.py`
+/// Version 2 added the number of output buckets, version 1 files have a single one.
+/// Version 3 stores the layer sizes of each bucket, before all buckets had the same
+const VERSION: u32 = 3;
+
+/// Activation functions, stored as a byte in the header
+const ACTIVATION_CRELU: u8 = 0;
+
+/// Description of the network stored at the start of a .nn file
+struct ModelHeader {
+    feature_set: String,
+    num_features: usize,
+    /// Size of each accumulator
+    num_l1: usize,
+    /// Outputs of each linear layer after the accumulators, for each output bucket (selected by material)
+    stack_sizes: Vec<Vec<usize>>,
+}
+
+impl ModelHeader {
+    /// Header where every bucket has the same layers, from the number of features and the outputs of each linear layer
+    fn with_shared_sizes(
+        feature_set: String,
+        num_buckets: usize,
+        layer_sizes: Vec<usize>,
+    ) -> io::Result<Self> {
+        let [num_features, num_l1, ref stack_sizes @ ..] = layer_sizes[..] else {
+            return Err(invalid_data(format!(
+                "Expected at least 2 linear layers, found {}",
+                layer_sizes.len().saturating_sub(1)
+            )));
+        };
+
+        Ok(ModelHeader {
+            feature_set,
+            num_features,
+            num_l1,
+            stack_sizes: vec![stack_sizes.to_vec(); num_buckets],
+        })
+    }
+
+    /// Reads the header of a versioned file, it also verifies the checksum of the whole file
+    /// magic (4) | version (u32) | crc32 (u32) of everything after it
+    /// feature set name length (u32) | feature set name (utf-8)
+    /// activation (u8) | log2 hidden weight scale (u8) | log2 output weight scale (u8)
+    /// version 3: number of output buckets (u32) | number of features (u32) | L1 size (u32) |
+    ///            for each bucket: number of linear layers after L1 (u32) | outputs of each layer (u32 each)
+    /// version 1 and 2: number of output buckets (u32, only version 2) | number of linear layers (u32) |
+    ///                  number of features (u32) | outputs of each layer (u32 each), the same for every bucket
+    fn read(cursor: &mut Cursor<&[u8]>) -> io::Result<Self> {
+        cursor.set_position(MAGIC.len() as u64);
+
+        let version = cursor.read_u32::<LittleEndian>()?;
+        if version == 0 || version > VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported .nn version {} (expected {})",
+                version, VERSION
+            )));
+        }
+
+        let checksum = cursor.read_u32::<LittleEndian>()?;
+        if crc32(&cursor.get_ref()[cursor.position() as usize..]) != checksum {
+            return Err(invalid_data("Checksum mismatch, the .nn file is corrupted"));
+        }
+
+        // lengths are capped to the file size, so a wrong length fails reading instead of allocating too much
+        let name_len = cursor.read_u32::<LittleEndian>()? as usize;
+        let mut name = vec![0; name_len.min(cursor.get_ref().len())];
+        cursor.read_exact(&mut name)?;
+        let feature_set = String::from_utf8(name).map_err(invalid_data)?;
+
+        let activation = cursor.read_u8()?;
+        if activation != ACTIVATION_CRELU {
+            return Err(invalid_data(format!(
+                "Unknown activation function {}",
+                activation
+            )));
+        }
+
+        // the kernels are compiled for fixed quantization scales
+        let log2_hidden_weight_scale = cursor.read_u8()? as i32;
+        let log2_output_weight_scale = cursor.read_u8()? as i32;
+        if log2_hidden_weight_scale != LOG2_HIDDEN_WEIGHT_SCALE
+            || log2_output_weight_scale != LOG2_OUTPUT_WEIGHT_SCALE
+        {
+            return Err(invalid_data(format!(
+                "Unsupported quantization scales 2^{} and 2^{} (expected 2^{} and 2^{})",
+                log2_hidden_weight_scale,
+                log2_output_weight_scale,
+                LOG2_HIDDEN_WEIGHT_SCALE,
+                LOG2_OUTPUT_WEIGHT_SCALE
+            )));
+        }
+
+        let num_buckets = if version >= 2 {
+            cursor.read_u32::<LittleEndian>()? as usize
+        } else {
+            1
+        };
+
+        if version < 3 {
+            let layer_sizes = read_sizes(cursor, 1)?;
+            // capped like the lengths, a wrong number of buckets fails the parameters size check
+            let num_buckets = num_buckets.min(cursor.get_ref().len());
+            return Self::with_shared_sizes(feature_set, num_buckets, layer_sizes);
+        }
+
+        let num_features = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_l1 = cursor.read_u32::<LittleEndian>()? as usize;
+        let stack_sizes = (0..num_buckets.min(cursor.get_ref().len()))
+            .map(|_| read_sizes(cursor, 0))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(ModelHeader {
+            feature_set,
+            num_features,
+            num_l1,
+            stack_sizes,
+        })
+    }
+
+    /// Reads the header of a legacy file (before versioning)
+    /// feature set name (null-terminated) | number of features (u32) | L1 size (u32) | L2 size (u32)
+    fn read_legacy(cursor: &mut Cursor<&[u8]>) -> io::Result<Self> {
+        let mut str_buffer = Vec::new();
+        cursor.read_until(0, &mut str_buffer)?;
+        if str_buffer.pop() != Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Missing null byte after the feature set name",
+            ));
+        }
+        let feature_set = String::from_utf8(str_buffer).map_err(invalid_data)?;
+
+        let num_features = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_l1 = cursor.read_u32::<LittleEndian>()? as usize;
+        let num_l2 = cursor.read_u32::<LittleEndian>()? as usize;
+
+        Ok(ModelHeader {
+            feature_set,
+            num_features,
+            num_l1,
+            // the output was always a single neuron
+            stack_sizes: vec![vec![num_l2, 1]],
+        })
+    }
+}
+
+/// Reads the number of linear layers followed by their sizes, `extra` more sizes than layers are read
+fn read_sizes(cursor: &mut Cursor<&[u8]>, extra: usize) -> io::Result<Vec<usize>> {
+    // lengths are capped to the file size, like the feature set name
+    let num_layers = cursor.read_u32::<LittleEndian>()? as usize;
+    (0..num_layers.min(cursor.get_ref().len()) + extra)
+        .map(|_| Ok(cursor.read_u32::<LittleEndian>()? as usize))
+        .collect()
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// CRC-32 (IEEE), the same as Python's `zlib.crc32`
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB88320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !data.iter().fold(!0, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
 /// A linear layer in the network
 struct LinearLayer<W, B> {
     num_inputs: usize,
@@ -14,43 +242,50 @@ struct LinearLayer<W, B> {
 
     weight: Tensor<W>, // num_inputs * num_outputs
     bias: Tensor<B>,   // num_outputs
-
-    // this buffer is used by the previous layer to prepare the data for this layer (to avoid allocations)
-    // note: this is unused in the feature transform layer :(
-    input_buffer: Tensor<W>,
-    // this buffer is used just after computing the linear layer, before applying the activation
-    intermediate_buffer: Tensor<B>,
 }
 
 impl<W, B> LinearLayer<W, B> {
-    fn new(cursor: &mut Cursor<&[u8]>, num_inputs: usize, num_outputs: usize) -> Self {
-        Self {
+    fn new(cursor: &mut Cursor<&[u8]>, num_inputs: usize, num_outputs: usize) -> io::Result<Self> {
+        Ok(Self {
             num_inputs,
             num_outputs,
 
-            weight: Tensor::from_cursor(cursor, num_inputs * num_outputs).unwrap(),
-            bias: Tensor::from_cursor(cursor, num_outputs).unwrap(),
-
-            input_buffer: Tensor::zeros(num_inputs),
-            intermediate_buffer: Tensor::zeros(num_outputs),
-        }
+            weight: Tensor::from_cursor(cursor, num_inputs * num_outputs)?,
+            bias: Tensor::from_cursor(cursor, num_outputs)?,
+        })
     }
 }
 
 impl LinearLayer<i8, i32> {
-    /// Forward pass of a hidden layer, using the input buffer and storing the result in the intermediate buffer.
-    unsafe fn forward_hidden(&self) {
+    /// Forward pass of a hidden layer, reading `num_inputs` elements from input and writing `num_outputs` elements to output.
+    unsafe fn forward_hidden(&self, backend: SimdBackend, input: *const i8, output: *mut i32) {
         linear(
+            backend,
             self.num_inputs,
             self.num_outputs,
-            self.input_buffer.as_ptr(),
+            input,
             self.weight.as_ptr(),
             self.bias.as_ptr(),
-            self.intermediate_buffer.as_mut_ptr(),
+            output,
         );
     }
 }
 
+/// Layers after the feature transformer, there is one stack per output bucket
+struct LayerStack {
+    /// Hidden layers after the accumulators, the first one takes both accumulators as input
+    hidden: Vec<LinearLayer<i8, i32>>,
+    linear_out: LinearLayer<i8, i32>,
+}
+
+/// Index of the output bucket for a board, based on the number of pieces.
+/// With 8 buckets it is `(pieces - 1) / 4`, like Stockfish
+pub fn output_bucket(board: &Board, num_buckets: usize) -> usize {
+    let num_pieces = board.occupied().count().clamp(1, 32);
+
+    ((num_pieces - 1) * num_buckets / 32).min(num_buckets - 1)
+}
+
 /// Neural Network Update Efficient (NNUE)
 /// https://github.com/official-stockfish/nnue-pytorch/blob/master/docs/nnue.md
 pub struct NnueModel {
@@ -58,10 +293,11 @@ pub struct NnueModel {
 
     pub arch: String,
     pub params: usize,
+    /// Instruction set used by the kernels, the best one supported by the CPU
+    pub simd: SimdBackend,
 
     linear1: LinearLayer<i16, i16>,
-    linear2: LinearLayer<i8, i32>,
-    linear_out: LinearLayer<i8, i32>,
+    stacks: Vec<LayerStack>,
 }
 
 impl NnueModel {
@@ -76,45 +312,154 @@ impl NnueModel {
 
     /// Loads a model from a .nn file in memory.
     /// Format description can be found in `scripts/lib/serialize.py`
+    /// Files without the magic bytes are read with the legacy (unversioned) format
     pub fn from_memory(buffer: &[u8]) -> io::Result<Self> {
         let mut cursor = Cursor::new(buffer);
 
-        // read feature set name
-        let mut str_buffer = Vec::new();
-        cursor.read_until(0, &mut str_buffer).unwrap();
-        str_buffer.pop(); // remove null byte
-        let feature_set_str = std::str::from_utf8(&str_buffer).unwrap();
+        let header = if buffer.starts_with(MAGIC) {
+            ModelHeader::read(&mut cursor)?
+        } else {
+            ModelHeader::read_legacy(&mut cursor)?
+        };
+
+        let feature_set = try_build_feature_set(&header.feature_set).map_err(invalid_data)?;
+
+        let ModelHeader {
+            num_features,
+            num_l1,
+            ref stack_sizes,
+            ..
+        } = header;
+        if num_features != feature_set.num_features() as usize {
+            return Err(invalid_data(format!(
+                "The feature set {} has {} features, but the network expects {}",
+                header.feature_set,
+                feature_set.num_features(),
+                num_features
+            )));
+        }
+        // every stack needs a single output neuron at the end
+        if num_l1 == 0
+            || stack_sizes.is_empty()
+            || stack_sizes
+                .iter()
+                .any(|sizes| sizes.contains(&0) || sizes.last() != Some(&1))
+        {
+            return Err(invalid_data(format!(
+                "Invalid layer sizes: L1 {} and stacks {:?}",
+                num_l1, stack_sizes
+            )));
+        }
 
-        // read network sizes
-        let num_features = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let num_l1 = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let num_l2 = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-        let num_out = 1;
+        // (inputs, outputs) of every layer after the first one in each stack, the accumulators of both sides are concatenated
+        let stack_shapes: Vec<Vec<(usize, usize)>> = stack_sizes
+            .iter()
+            .map(|sizes| {
+                std::iter::once(2 * num_l1)
+                    .chain(sizes.iter().copied())
+                    .zip(sizes.iter().copied())
+                    .collect()
+            })
+            .collect();
+
+        // make sure the parameters are all there before allocating anything,
+        // so a corrupted size can't make us allocate a huge amount of memory
+        let stacks_size = stack_shapes
+            .iter()
+            .flatten()
+            .map(|&(num_inputs, num_outputs)| {
+                num_inputs
+                    .checked_mul(num_outputs)
+                    .and_then(|w| w.checked_add(num_outputs * 4))
+            })
+            .try_fold(0usize, |total, layer| total.checked_add(layer?));
+        let params_size = num_features
+            .checked_mul(num_l1 * 2)
+            .and_then(|w| w.checked_add(num_l1 * 2))
+            .zip(stacks_size)
+            .and_then(|(l1, stacks)| l1.checked_add(stacks));
+        let remaining = buffer.len() - cursor.position() as usize;
+        if params_size != Some(remaining) {
+            return Err(invalid_data(format!(
+                "The file has {} bytes of parameters, but L1 {} and stacks {:?} require {:?}",
+                remaining, num_l1, stack_sizes, params_size
+            )));
+        }
 
-        let feature_set = build_feature_set(feature_set_str);
-        assert_eq!(num_features, feature_set.num_features() as usize);
+        let linear1 = LinearLayer::new(&mut cursor, num_features, num_l1)?;
+        let stacks = stack_shapes
+            .iter()
+            .map(|shapes| {
+                let mut hidden = shapes
+                    .iter()
+                    .map(|&(num_inputs, num_outputs)| {
+                        LinearLayer::new(&mut cursor, num_inputs, num_outputs)
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                let linear_out = hidden.pop().unwrap();
+
+                Ok(LayerStack { hidden, linear_out })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let params = num_features * num_l1
+            + num_l1
+            + stack_shapes
+                .iter()
+                .flatten()
+                .map(|(num_inputs, num_outputs)| num_inputs * num_outputs + num_outputs)
+                .sum::<usize>();
+        let stack_archs: Vec<String> = stack_sizes
+            .iter()
+            .map(|sizes| {
+                sizes
+                    .iter()
+                    .map(|size| size.to_string())
+                    .collect::<Vec<_>>()
+                    .join("→")
+            })
+            .collect();
+        let arch = if stack_archs.len() == 1 {
+            format!(
+                "({}[{}]→{})x2→{}",
+                header.feature_set, num_features, num_l1, stack_archs[0]
+            )
+        } else if stack_archs.iter().all(|arch| *arch == stack_archs[0]) {
+            format!(
+                "({}[{}]→{})x2→({})x{}",
+                header.feature_set,
+                num_features,
+                num_l1,
+                stack_archs[0],
+                stack_archs.len()
+            )
+        } else {
+            // a different stack per bucket
+            format!(
+                "({}[{}]→{})x2→({})",
+                header.feature_set,
+                num_features,
+                num_l1,
+                stack_archs.join("|")
+            )
+        };
 
         Ok(Self {
             feature_set,
-            linear1: LinearLayer::new(&mut cursor, num_features, num_l1),
-            linear2: LinearLayer::new(&mut cursor, 2 * num_l1, num_l2),
-            linear_out: LinearLayer::new(&mut cursor, num_l2, num_out),
-
-            arch: format!("({}[{}]→{})x2→{}→1", feature_set_str, num_features, num_l1, num_l2),
-            params: 
-                // l1
-                num_features * num_l1 + num_l1 +
-                // l2
-                (2 * num_l1) * num_l2 + num_l2 +
-                // out
-                num_out * num_l2 + num_l2 + num_out,
+            linear1,
+            stacks,
+
+            simd: SimdBackend::detect(),
+            arch,
+            params,
         })
     }
 
     /// Refreshes the accumulator with the given features (slow)
-    pub fn refresh_accumulator(&self, accumulator: &Tensor<i16>, active_features: &[u16]) {
+    pub fn refresh_accumulator(&self, accumulator: &mut Tensor<i16>, active_features: &[u16]) {
         unsafe {
             linear_partial_refresh(
+                self.simd,
                 self.linear1.num_inputs,
                 self.linear1.num_outputs,
                 active_features,
@@ -130,12 +475,13 @@ impl NnueModel {
     /// ensure that rows are not added/removed twice.
     pub fn update_accumulator(
         &self,
-        accumulator: &Tensor<i16>,
+        accumulator: &mut Tensor<i16>,
         added_features: &[u16],
         removed_features: &[u16],
     ) {
         unsafe {
             linear_partial_update(
+                self.simd,
                 self.linear1.num_inputs,
                 self.linear1.num_outputs,
                 added_features,
@@ -146,38 +492,71 @@ impl NnueModel {
         }
     }
 
-    /// Forward pass of the network, skipping the first layer and instead taking the accumulated values for each side
-    pub fn forward(&self, to_move_accum: &Tensor<i16>, not_to_move_accum: &Tensor<i16>) -> i32 {
-        unsafe {
+    /// Number of output buckets (layer stacks after the accumulators)
+    pub fn num_buckets(&self) -> usize {
+        self.stacks.len()
+    }
+
+    /// Index of the output bucket to use for the given board
+    pub fn output_bucket(&self, board: &Board) -> usize {
+        output_bucket(board, self.stacks.len())
+    }
+
+    /// Forward pass of the network, skipping the first layer and instead taking the accumulated values for each side.
+    /// The layers after the accumulators are the ones of the given output bucket
+    pub fn forward(
+        &self,
+        to_move_accum: &Tensor<i16>,
+        not_to_move_accum: &Tensor<i16>,
+        bucket: usize,
+    ) -> i32 {
+        let l1_out = self.linear1.num_outputs; // size of each accumulator
+        let stack = &self.stacks[bucket];
+
+        FORWARD_BUFFERS.with_borrow_mut(|buffers| unsafe {
+            let max_hidden = stack.hidden.iter().map(|layer| layer.num_outputs).max();
+            buffers.reserve(
+                (2 * l1_out).max(max_hidden.unwrap_or(0)),
+                max_hidden.unwrap_or(0).max(stack.linear_out.num_outputs),
+            );
+
+            let activations = &mut buffers.activations;
+            let outputs = &mut buffers.outputs;
+
             // layer 1 already computed in accumulator
             let to_move_accum = to_move_accum.as_ptr();
             let not_to_move_accum = not_to_move_accum.as_ptr();
-            let l1_out = self.linear1.num_outputs; // size of each accumulator
 
-            // split the input buffer of the layer 2 into two parts
-            let (to_move, not_to_move) = self
-                .linear2
-                .input_buffer
-                .as_mut_slice()
-                .split_at_mut(l1_out);
+            // split the input of the layer 2 into two parts
+            let (to_move, not_to_move) = activations.as_mut_slice().split_at_mut(l1_out);
 
             // fill the input to the layer 2 doing the crelu of the two accumulators (output of the first layer)
-            crelu_16(l1_out, to_move_accum, to_move.as_mut_ptr());
-            crelu_16(l1_out, not_to_move_accum, not_to_move.as_mut_ptr());
-
-            // forward layer 2
-            self.linear2.forward_hidden();
-            crelu_32(
-                self.linear2.num_outputs,
-                self.linear2.intermediate_buffer.as_ptr(),
-                self.linear_out.input_buffer.as_mut_ptr(),
+            crelu_16(self.simd, l1_out, to_move_accum, to_move.as_mut_ptr());
+            crelu_16(
+                self.simd,
+                l1_out,
+                not_to_move_accum,
+                not_to_move.as_mut_ptr(),
             );
 
+            // forward hidden layers, each one reads its input from the activations of the previous one
+            for layer in &stack.hidden {
+                layer.forward_hidden(self.simd, activations.as_ptr(), outputs.as_mut_ptr());
+                crelu_32(
+                    self.simd,
+                    layer.num_outputs,
+                    outputs.as_ptr(),
+                    activations.as_mut_ptr(),
+                );
+            }
+
             // forward output layer
-            self.linear_out.forward_hidden();
+            stack
+                .linear_out
+                .forward_hidden(self.simd, activations.as_ptr(), outputs.as_mut_ptr());
 
-            self.linear_out.intermediate_buffer.as_slice()[0]
-        }
+            outputs.as_slice()[0]
+        })
     }
 
     pub fn get_feature_set(&self) -> &FeatureSet {
@@ -189,8 +568,111 @@ impl NnueModel {
     }
 }
 
+/// Helpers to build networks in tests, the tests of other crates can use them with the `test-utils` feature
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils {
+    use super::*;
+
+    /// Writes a versioned file like `scripts/lib/serialize.py`, the parameters are copied as they are.
+    /// Before version 3 only the sizes of the first stack are written, since all of them were the same
+    pub fn write_versioned(
+        version: u32,
+        feature_set: &str,
+        num_features: u32,
+        num_l1: u32,
+        stack_sizes: &[Vec<u32>],
+        params: &[u8],
+    ) -> Vec<u8> {
+        let mut content = Vec::new();
+        content.extend((feature_set.len() as u32).to_le_bytes());
+        content.extend(feature_set.as_bytes());
+        content.push(ACTIVATION_CRELU);
+        content.push(LOG2_HIDDEN_WEIGHT_SCALE as u8);
+        content.push(LOG2_OUTPUT_WEIGHT_SCALE as u8);
+        if version >= 2 {
+            content.extend((stack_sizes.len() as u32).to_le_bytes());
+        }
+        if version >= 3 {
+            content.extend(num_features.to_le_bytes());
+            content.extend(num_l1.to_le_bytes());
+            for sizes in stack_sizes {
+                content.extend((sizes.len() as u32).to_le_bytes());
+                for size in sizes {
+                    content.extend(size.to_le_bytes());
+                }
+            }
+        } else {
+            content.extend((stack_sizes[0].len() as u32 + 1).to_le_bytes());
+            for size in [num_features, num_l1].iter().chain(&stack_sizes[0]) {
+                content.extend(size.to_le_bytes());
+            }
+        }
+        content.extend(params);
+
+        let mut file = MAGIC.to_vec();
+        file.extend(version.to_le_bytes());
+        file.extend(crc32(&content).to_le_bytes());
+        file.extend(content);
+        file
+    }
+
+    /// Builds a versioned file with random parameters, with the given layer sizes after L1 for each bucket.
+    /// Returns the file and the parameters of each layer: the first one, then the stack of each bucket
+    pub fn random_network(
+        feature_set: &str,
+        num_features: u32,
+        num_l1: u32,
+        stack_sizes: &[Vec<u32>],
+    ) -> (Vec<u8>, Vec<Vec<i32>>) {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut layers = Vec::new();
+        let mut params = Vec::new();
+
+        // (inputs, outputs) of each layer, the first hidden layer takes both accumulators
+        let shapes = std::iter::once((num_features as usize, num_l1 as usize)).chain(
+            stack_sizes.iter().flat_map(|sizes| {
+                std::iter::once(2 * num_l1)
+                    .chain(sizes.iter().copied())
+                    .zip(sizes.iter().copied())
+                    .map(|(num_inputs, num_outputs)| (num_inputs as usize, num_outputs as usize))
+            }),
+        );
+
+        for (i, (num_inputs, num_outputs)) in shapes.enumerate() {
+            // weights followed by biases, small enough to never saturate
+            let layer: Vec<i32> = (0..num_inputs * num_outputs)
+                .map(|_| rng.gen_range(-8..=8))
+                .chain((0..num_outputs).map(|_| rng.gen_range(-500..=500)))
+                .collect();
+            for (j, &value) in layer.iter().enumerate() {
+                if i == 0 {
+                    params.extend((value as i16).to_le_bytes());
+                } else if j < num_inputs * num_outputs {
+                    params.push(value as i8 as u8);
+                } else {
+                    params.extend(value.to_le_bytes());
+                }
+            }
+            layers.push(layer);
+        }
+
+        let file = write_versioned(
+            VERSION,
+            feature_set,
+            num_features,
+            num_l1,
+            stack_sizes,
+            &params,
+        );
+        (file, layers)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::test_utils::*;
     use super::*;
 
     /// Make sure that refreshing and updating (adding/removing features) gives the same output
@@ -217,22 +699,259 @@ mod tests {
             548, 266, 67, 290, 78, 72, 23, 79, 338, 81, 86, 328, 631, 702, 419, 616,
         ];
 
-        let accum_updates = Tensor::zeros(nnue_model.get_num_features());
-        nnue_model.refresh_accumulator(&accum_updates, initial_features.as_slice());
-        nnue_model.update_accumulator(&accum_updates, &[213, 512, 97, 120], &[631, 702, 419, 616]);
-        nnue_model.update_accumulator(&accum_updates, &[275, 428, 265, 466], &[6, 728, 683, 723]);
-        nnue_model.update_accumulator(&accum_updates, &[363, 640, 431, 350], &[0, 577, 491, 660]);
-        nnue_model.update_accumulator(&accum_updates, &[494, 734, 553, 544], &[547, 226, 79, 651]);
-        nnue_model.update_accumulator(&accum_updates, &[54, 569, 582, 281], &[73, 701, 466, 260]);
-        nnue_model.update_accumulator(&accum_updates, &[764, 148, 174, 84], &[279, 225, 569, 149]);
-        nnue_model.update_accumulator(&accum_updates, &[396, 473, 314, 250], &[133, 507, 492, 266]);
-        nnue_model.update_accumulator(&accum_updates, &[244, 211, 620, 39], &[484, 523, 640, 27]);
-        nnue_model.update_accumulator(&accum_updates, &[181, 487, 168, 470], &[553, 755, 652, 537]);
-        nnue_model.update_accumulator(&accum_updates, &[361, 324, 728, 10], &[47, 726, 333, 3]);
-
-        let accum_refresh = Tensor::zeros(nnue_model.get_num_features());
-        nnue_model.refresh_accumulator(&accum_refresh, &all_features.as_slice());
+        let accum_updates = &mut Tensor::zeros(nnue_model.get_num_features());
+        nnue_model.refresh_accumulator(accum_updates, initial_features.as_slice());
+        nnue_model.update_accumulator(accum_updates, &[213, 512, 97, 120], &[631, 702, 419, 616]);
+        nnue_model.update_accumulator(accum_updates, &[275, 428, 265, 466], &[6, 728, 683, 723]);
+        nnue_model.update_accumulator(accum_updates, &[363, 640, 431, 350], &[0, 577, 491, 660]);
+        nnue_model.update_accumulator(accum_updates, &[494, 734, 553, 544], &[547, 226, 79, 651]);
+        nnue_model.update_accumulator(accum_updates, &[54, 569, 582, 281], &[73, 701, 466, 260]);
+        nnue_model.update_accumulator(accum_updates, &[764, 148, 174, 84], &[279, 225, 569, 149]);
+        nnue_model.update_accumulator(accum_updates, &[396, 473, 314, 250], &[133, 507, 492, 266]);
+        nnue_model.update_accumulator(accum_updates, &[244, 211, 620, 39], &[484, 523, 640, 27]);
+        nnue_model.update_accumulator(accum_updates, &[181, 487, 168, 470], &[553, 755, 652, 537]);
+        nnue_model.update_accumulator(accum_updates, &[361, 324, 728, 10], &[47, 726, 333, 3]);
+
+        let mut accum_refresh = Tensor::zeros(nnue_model.get_num_features());
+        nnue_model.refresh_accumulator(&mut accum_refresh, &all_features.as_slice());
 
         assert_eq!(accum_updates.as_slice(), accum_refresh.as_slice()); // thus forward gives the same output
     }
+
+    /// The embedded network must evaluate exactly the same with every supported instruction set
+    #[test]
+    fn test_backends_forward() {
+        let mut nnue_model =
+            NnueModel::from_memory(include_bytes!("../../../models/best.nn")).unwrap();
+
+        let features = [
+            [490, 254, 362, 225, 3, 279, 516, 482],
+            [668, 324, 624, 690, 473, 204, 97, 336],
+        ];
+        let mut expected = None;
+
+        for backend in SimdBackend::ALL.into_iter().filter(|b| b.is_supported()) {
+            nnue_model.simd = backend;
+
+            let mut to_move = Tensor::zeros(nnue_model.get_num_features());
+            let mut not_to_move = Tensor::zeros(nnue_model.get_num_features());
+            nnue_model.refresh_accumulator(&mut to_move, &features[0]);
+            nnue_model.refresh_accumulator(&mut not_to_move, &features[1]);
+            nnue_model.update_accumulator(&mut to_move, &[213, 512], &[3, 279]);
+
+            let output = nnue_model.forward(&to_move, &not_to_move, 0);
+            assert_eq!(*expected.get_or_insert(output), output, "{}", backend);
+        }
+    }
+
+    /// Converts a legacy .nn file into the given version of the format,
+    /// keeping only the first `num_layers` linear layers in the header
+    fn to_versioned(legacy: &[u8], feature_set: &str, version: u32, num_layers: usize) -> Vec<u8> {
+        let name_end = legacy.iter().position(|&b| b == 0).unwrap();
+        let mut cursor = Cursor::new(&legacy[name_end + 1..]);
+        let sizes = [0; 3].map(|_| cursor.read_u32::<LittleEndian>().unwrap());
+        // the output was always a single neuron
+        let stack_sizes = vec![sizes[2], 1][..num_layers - 1].to_vec();
+        let params = &legacy[name_end + 13..];
+
+        write_versioned(
+            version,
+            feature_set,
+            sizes[0],
+            sizes[1],
+            &[stack_sizes],
+            params,
+        )
+    }
+
+    /// Networks with any number of hidden layers and buckets must match a plain integer implementation
+    #[test]
+    fn test_hidden_layers() {
+        let features = [[3, 279, 516, 482], [668, 324, 624, 690]];
+
+        for (num_l1, stack_sizes, arch) in [
+            (32, vec![vec![1]], "(all[768]→32)x2→1"),
+            (64, vec![vec![32, 1]], "(all[768]→64)x2→32→1"),
+            (64, vec![vec![32, 32, 1]], "(all[768]→64)x2→32→32→1"),
+            (32, vec![vec![16, 8, 4, 1]], "(all[768]→32)x2→16→8→4→1"),
+            (32, vec![vec![16, 1]; 8], "(all[768]→32)x2→(16→1)x8"),
+            (64, vec![vec![32, 32, 1]; 3], "(all[768]→64)x2→(32→32→1)x3"),
+            // a different L2 size per bucket
+            (
+                64,
+                vec![vec![32, 1], vec![16, 1], vec![8, 32, 1]],
+                "(all[768]→64)x2→(32→1|16→1|8→32→1)",
+            ),
+        ] {
+            let (file, layers) = random_network("all", 768, num_l1, &stack_sizes);
+            let model = NnueModel::from_memory(&file).unwrap();
+
+            let num_l1 = num_l1 as usize;
+            let expected_params: usize = layers.iter().map(|layer| layer.len()).sum();
+            assert_eq!(model.params, expected_params);
+            assert_eq!(model.num_buckets(), stack_sizes.len());
+            assert_eq!(model.arch, arch);
+
+            let mut to_move = Tensor::zeros(model.get_num_features());
+            let mut not_to_move = Tensor::zeros(model.get_num_features());
+            model.refresh_accumulator(&mut to_move, &features[0]);
+            model.refresh_accumulator(&mut not_to_move, &features[1]);
+
+            let mut stack_start = 1;
+            for (bucket, sizes) in stack_sizes.iter().enumerate() {
+                // reference forward pass
+                let mut activations: Vec<i32> = features
+                    .iter()
+                    .flat_map(|active| {
+                        let layer = &layers[0];
+                        (0..num_l1).map(move |o| {
+                            active.iter().fold(layer[768 * num_l1 + o], |sum, &a| {
+                                sum + layer[a as usize * num_l1 + o]
+                            })
+                        })
+                    })
+                    .map(|x| x.clamp(0, 127))
+                    .collect();
+                let stack = &layers[stack_start..stack_start + sizes.len()];
+                stack_start += sizes.len();
+                for (i, layer) in stack.iter().enumerate() {
+                    let num_outputs = sizes[i] as usize;
+                    let num_inputs = activations.len();
+                    let is_output = i == sizes.len() - 1;
+                    activations = (0..num_outputs)
+                        .map(|o| {
+                            let sum = (0..num_inputs)
+                                .fold(layer[num_inputs * num_outputs + o], |sum, j| {
+                                    sum + activations[j] * layer[o * num_inputs + j]
+                                });
+                            if is_output {
+                                sum >> LOG2_OUTPUT_WEIGHT_SCALE
+                            } else {
+                                (sum >> LOG2_HIDDEN_WEIGHT_SCALE).clamp(0, 127)
+                            }
+                        })
+                        .collect();
+                }
+
+                assert_eq!(
+                    model.forward(&to_move, &not_to_move, bucket),
+                    activations[0],
+                    "{} bucket {}",
+                    arch,
+                    bucket
+                );
+            }
+        }
+    }
+
+    /// Buckets go from 0 with a full board to the last one with few pieces
+    #[test]
+    fn test_output_bucket() {
+        use shakmaty::fen::Fen;
+
+        let board = |fen: &str| fen.parse::<Fen>().unwrap().into_setup().board;
+
+        let initial = Board::default();
+        assert_eq!(output_bucket(&initial, 1), 0);
+        assert_eq!(output_bucket(&initial, 8), 7);
+        assert_eq!(output_bucket(&board("8/8/4k3/8/8/3K4/8/8 w - - 0 1"), 8), 0);
+        assert_eq!(output_bucket(&board("8/8/4k3/8/8/3K4/8/8 w - - 0 1"), 1), 0);
+        // (pieces - 1) / 4
+        for (fen, bucket) in [
+            ("8/8/4k3/8/3P4/3K4/8/8 w - - 0 1", 0),
+            ("8/8/4k3/3p4/3P4/3K4/8/8 w - - 0 1", 0),
+            ("8/8/4k3/3p4/3P4/3K1R2/8/8 w - - 0 1", 1),
+            ("rnbqkbnr/pppppppp/8/8/8/8/8/4K3 w kq - 0 1", 4),
+        ] {
+            assert_eq!(output_bucket(&board(fen), 8), bucket, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    /// The same network must give the same output in every version of the format
+    #[test]
+    fn test_versioned_format() {
+        let legacy = include_bytes!("../../../models/best.nn");
+        let legacy_model = NnueModel::from_memory(legacy).unwrap();
+        let feature_set = legacy_model.arch[1..].split('[').next().unwrap();
+
+        let mut legacy_accumulator = Tensor::zeros(legacy_model.get_num_features());
+        legacy_model.refresh_accumulator(&mut legacy_accumulator, &[3, 279, 516, 482]);
+
+        for version in 1..=VERSION {
+            let file = to_versioned(legacy, feature_set, version, 3);
+            let model = NnueModel::from_memory(&file).unwrap();
+
+            assert_eq!(model.arch, legacy_model.arch);
+            assert_eq!(model.params, legacy_model.params);
+
+            let mut accumulator = Tensor::zeros(model.get_num_features());
+            model.refresh_accumulator(&mut accumulator, &[3, 279, 516, 482]);
+
+            assert_eq!(
+                model.forward(&accumulator, &accumulator, 0),
+                legacy_model.forward(&legacy_accumulator, &legacy_accumulator, 0),
+                "version {}",
+                version
+            );
+        }
+    }
+
+    /// Broken files must be reported as errors, not panics
+    #[test]
+    fn test_invalid_files() {
+        let legacy = include_bytes!("../../../models/best.nn");
+        let feature_set = NnueModel::from_memory(legacy).unwrap().arch[1..]
+            .split('[')
+            .next()
+            .unwrap()
+            .to_string();
+        let versioned = to_versioned(legacy, &feature_set, VERSION, 3);
+
+        let error_kind = |buffer: &[u8]| NnueModel::from_memory(buffer).err().map(|err| err.kind());
+
+        // truncated files
+        for len in [0, 1, 3, 4, 10, 20, 40, 1000, legacy.len() - 1] {
+            assert!(
+                error_kind(&legacy[..len]).is_some(),
+                "legacy truncated at {}",
+                len
+            );
+            assert!(
+                error_kind(&versioned[..len]).is_some(),
+                "truncated at {}",
+                len
+            );
+        }
+
+        // trailing data
+        let mut longer = legacy.to_vec();
+        longer.push(0);
+        assert_eq!(error_kind(&longer), Some(io::ErrorKind::InvalidData));
+
+        // corrupted parameter
+        let mut corrupted = versioned.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(error_kind(&corrupted), Some(io::ErrorKind::InvalidData));
+
+        // unknown version
+        let mut future = versioned.clone();
+        future[4] = VERSION as u8 + 1;
+        assert_eq!(error_kind(&future), Some(io::ErrorKind::InvalidData));
+
+        // mismatched header
+        for file in [
+            to_versioned(legacy, "all+xyz", VERSION, 3),
+            to_versioned(legacy, "all+h", VERSION, 3),
+            to_versioned(legacy, &feature_set, VERSION, 2),
+            to_versioned(legacy, &feature_set, 2, 2),
+        ] {
+            assert_eq!(error_kind(&file), Some(io::ErrorKind::InvalidData));
+        }
+    }
 }
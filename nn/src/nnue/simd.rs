@@ -0,0 +1,72 @@
+use std::fmt::{Display, Formatter};
+
+/// Instruction set used by the inference kernels, from the least to the most capable.
+/// All of them produce exactly the same results, they only differ in speed.
+/// Only x86-64 has SIMD kernels, other architectures (e.g. aarch64, there is no NEON backend) run the scalar ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdBackend {
+    /// Plain Rust, runs everywhere
+    Scalar,
+    /// 128-bit registers
+    Sse41,
+    /// 256-bit registers
+    Avx2,
+    /// 512-bit registers, with the `vpdpbusd` dot product
+    Avx512Vnni,
+}
+
+impl SimdBackend {
+    /// All backends, from the least to the most capable
+    pub const ALL: [SimdBackend; 4] = [
+        SimdBackend::Scalar,
+        SimdBackend::Sse41,
+        SimdBackend::Avx2,
+        SimdBackend::Avx512Vnni,
+    ];
+
+    /// The most capable backend supported by the CPU
+    pub fn detect() -> SimdBackend {
+        Self::ALL
+            .into_iter()
+            .rev()
+            .find(|backend| backend.is_supported())
+            .unwrap()
+    }
+
+    /// Whether the CPU running the program supports the backend
+    pub fn is_supported(self) -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            match self {
+                SimdBackend::Scalar => true,
+                SimdBackend::Sse41 => is_x86_feature_detected!("sse4.1"),
+                SimdBackend::Avx2 => is_x86_feature_detected!("avx2"),
+                // the AVX-512 backend falls back to AVX2 for some kernels
+                SimdBackend::Avx512Vnni => {
+                    is_x86_feature_detected!("avx2")
+                        && is_x86_feature_detected!("avx512f")
+                        && is_x86_feature_detected!("avx512bw")
+                        && is_x86_feature_detected!("avx512vnni")
+                }
+            }
+        }
+
+        // no SIMD kernels for other architectures yet
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            self == SimdBackend::Scalar
+        }
+    }
+}
+
+impl Display for SimdBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SimdBackend::Scalar => "scalar",
+            SimdBackend::Sse41 => "sse4.1",
+            SimdBackend::Avx2 => "avx2",
+            SimdBackend::Avx512Vnni => "avx512-vnni",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -5,7 +5,7 @@ use std::{
     fmt::Formatter,
 };
 
-/// Tensor of elements of type T, with memory aligned to 32 bits (needed for SIMD operations)
+/// Tensor of elements of type T, with memory aligned to 64 bytes (needed for SIMD operations, up to AVX-512)
 pub struct Tensor<T> {
     layout: Layout,
     data: *mut T,
@@ -14,7 +14,7 @@ pub struct Tensor<T> {
 impl<T> Tensor<T> {
     /// Initializes a zeroed tensor
     pub fn zeros(size: usize) -> Self {
-        let layout = Layout::from_size_align(size * std::mem::size_of::<T>(), 32).unwrap();
+        let layout = Layout::from_size_align(size * std::mem::size_of::<T>(), 64).unwrap();
         let data = unsafe { alloc(layout) } as *mut T;
         unsafe {
             std::ptr::write_bytes(data, 0, size);
@@ -37,7 +37,7 @@ impl<T> Tensor<T> {
         self.data as *const T
     }
 
-    pub fn as_mut_ptr(&self) -> *mut T {
+    pub fn as_mut_ptr(&mut self) -> *mut T {
         self.data
     }
 
@@ -45,7 +45,7 @@ impl<T> Tensor<T> {
         unsafe { std::slice::from_raw_parts(self.data, self.len()) }
     }
 
-    pub fn as_mut_slice(&self) -> &mut [T] {
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
         unsafe { std::slice::from_raw_parts_mut(self.data, self.len()) }
     }
 
@@ -54,6 +54,11 @@ impl<T> Tensor<T> {
     }
 }
 
+// Tensors own their memory like a `Box<[T]>`, so they can be moved between threads.
+// Writing requires `&mut self`, so sharing a `&Tensor` between threads only allows reading it.
+unsafe impl<T: Send> Send for Tensor<T> {}
+unsafe impl<T: Sync> Sync for Tensor<T> {}
+
 impl<T> Drop for Tensor<T> {
     fn drop(&mut self) {
         unsafe {
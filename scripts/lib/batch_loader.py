@@ -36,15 +36,19 @@ class BatchLoader:
         input_loop: bool = False,
         batch_threads: int = 1,
         random_skipping: float = 0.0,
+        output_buckets: int = 1,
     ):
         num_features = get_feature_set_size(feature_set)
+        words = math.ceil(num_features / 64)
 
+        # each position is encoded as the features of both sides followed by its output bucket
         if method == "pqr":
-            x_shape = (batch_size, 3, 2, math.ceil(num_features / 64))
+            x_shape = (batch_size, 3, 2 * words + 1)
             y_shape = (batch_size, 0)
         elif method == "eval":
-            x_shape = (batch_size, 2, math.ceil(num_features / 64))
+            x_shape = (batch_size, 2 * words + 1)
             y_shape = (batch_size, 1)
+        self.words = words
 
         x_size = math.prod(x_shape) * 8  # 8 bytes per int64
         y_size = math.prod(y_shape) * 4  # 4 bytes per float32
@@ -65,6 +69,7 @@ class BatchLoader:
             "--feature-set=" + feature_set,
             "--threads=" + str(batch_threads),
             "--random-skipping=" + str(random_skipping),
+            "--output-buckets=" + str(output_buckets),
         ]
         if input_loop:
             self.cmd.append("--input-loop")
@@ -117,7 +122,7 @@ class BatchLoader:
     def next_batch(self):
         """
         Gets the next batch of samples.
-        Returns a Pytorch tensor containing the next batch of samples.
+        Returns Pytorch tensors with the features, the output bucket of each position and the targets.
         """
         # wait until batch is ready
         try:
@@ -132,7 +137,9 @@ class BatchLoader:
 
         # create PyTorch tensors using the numpy arrays.
         # this will copy the data into the device, so after this line we don't care about self.data/x/y
-        x_tensor = torch.tensor(self.x, dtype=torch.int64, device="cuda")
+        features = self.x[..., :2 * self.words].reshape(self.x.shape[:-1] + (2, self.words))
+        x_tensor = torch.tensor(features, dtype=torch.int64, device="cuda")
+        b_tensor = torch.tensor(self.x[..., 2 * self.words], dtype=torch.int64, device="cuda")
         y_tensor = torch.tensor(self.y, dtype=torch.float32, device="cuda")
 
         # release the shared memory for the generator to use.
@@ -143,7 +150,7 @@ class BatchLoader:
             # we don't care, it will be restarted if needed
             pass
 
-        return x_tensor, y_tensor
+        return x_tensor, b_tensor, y_tensor
 
     def cleanup(self):
         """
@@ -195,5 +202,5 @@ if __name__ == "__main__":
         offset += length # 30 GB
 
         for i in tqdm(range(1_000), desc=f"batch_threads={threads}"):
-            X, y = bl.next_batch()
+            X, b, y = bl.next_batch()
             X, y = X.cuda(), y.cuda()
@@ -25,8 +25,17 @@ def expand_batch(X: torch.Tensor, num_features: int):
 
     return X
 
+def select_bucket(x: torch.Tensor, buckets: torch.Tensor, num_buckets: int):
+    """
+    Keep only the outputs of the layer stack of each sample's bucket
+    """
+    # x.shape = [BATCH_SIZE, num_buckets * size]
+    x = x.view(x.shape[0], num_buckets, -1)
+    # x.shape = [BATCH_SIZE, num_buckets, size]
+    return x[torch.arange(x.shape[0], device=x.device), buckets]
+
 class NnueModel(nn.Module):
-    def __init__(self, num_features: int = 768, l1_size: int = 256, l2_size: int = 32):
+    def __init__(self, num_features: int = 768, l1_size: int = 256, l2_size: int = 32, extra_hidden_sizes: list[int] = [], num_buckets: int = 1):
         super(NnueModel, self).__init__()
 
         self.quantized_one = 127
@@ -37,20 +46,36 @@ class NnueModel(nn.Module):
         self.num_features = num_features
         self.l1_size = l1_size
         self.l2_size = l2_size
+        self.hidden_sizes = [l2_size] + list(extra_hidden_sizes)
+        self.num_buckets = num_buckets
 
+        # the layers after l1 have the outputs of every bucket stacked,
+        # each sample only uses the ones of its bucket
         self.l1 = nn.Linear(num_features, l1_size)
-        self.l2 = nn.Linear(l1_size * 2, l2_size)
-        self.output = nn.Linear(l2_size, 1)
+        self.l2 = nn.Linear(l1_size * 2, l2_size * num_buckets)
+        # layers after l2, empty in the default →32→1 head
+        self.extra = nn.ModuleList([
+            nn.Linear(num_inputs, num_outputs * num_buckets)
+            for num_inputs, num_outputs in zip(self.hidden_sizes, self.hidden_sizes[1:])
+        ])
+        self.output = nn.Linear(self.hidden_sizes[-1], num_buckets)
 
-    def forward(self, x):
+    def forward(self, x, buckets=None):
         x = self.l1(x)
         x = x.view(-1, self.l1_size * 2)
         x = torch.clamp(x, 0.0, 1.0) # Clipped ReLU
 
-        x = self.l2(x)
-        x = torch.clamp(x, 0.0, 1.0) # Clipped ReLU
+        if buckets is None:
+            buckets = torch.zeros(x.shape[0], dtype=torch.int64, device=x.device)
+        buckets = buckets.reshape(-1)
+
+        for layer in [self.l2, *self.extra]:
+            x = select_bucket(layer(x), buckets, self.num_buckets)
+            x = torch.clamp(x, 0.0, 1.0) # Clipped ReLU
+
+        x = select_bucket(self.output(x), buckets, self.num_buckets)
 
-        return self.output(x) * self.nnue2score
+        return x * self.nnue2score
 
     def clip_weights(self):
         # ft weights are NOT clamped, since they are stored with 16 bits
@@ -59,5 +84,6 @@ class NnueModel(nn.Module):
         hidden_clip = self.quantized_one / self.weight_scale_hidden
         output_clip = (self.quantized_one * self.quantized_one) * self.weight_scale_output
 
-        self.l2.weight.data.clamp_(-hidden_clip, hidden_clip)
+        for layer in [self.l2, *self.extra]:
+            layer.weight.data.clamp_(-hidden_clip, hidden_clip)
         self.output.weight.data.clamp_(-output_clip, output_clip)
@@ -1,20 +1,52 @@
+import math
+import zlib
+
 import torch
 
+MAGIC = b"NNUE"
+VERSION = 3
+ACTIVATION_CRELU = 0
+
 
 class NnueWriter:
+    """
+    Writes a model in the .nn format read by `nn/src/nnue/model.rs`
+    All values are little endian:
+
+    magic "NNUE" (4 bytes)
+    version (u32)
+    checksum (u32), CRC-32 of everything after it
+    feature set name length (u32), feature set name (utf-8)
+    activation (u8), 0 = clipped ReLU
+    log2 of the hidden weight scale (u8), log2 of the output weight scale (u8)
+    number of output buckets (u32)
+    number of features (u32), L1 size (u32)
+    for each bucket: number of linear layers after L1 (u32), followed by the number of outputs of each one (u32 each)
+    weights and biases of the first linear layer
+    weights and biases of the layers after it, for each bucket
+    """
+
     def __init__(self, model, feature_set_name):
         self.buf = bytearray()
 
-        self.buf.extend(bytes(feature_set_name, 'utf-8'))
-        self.buf.append(0) # null-terminated string
+        name = bytes(feature_set_name, 'utf-8')
+        self.buf.extend(len(name).to_bytes(4, byteorder='little', signed=False))
+        self.buf.extend(name)
 
-        for k in [
-            model.num_features,
-            model.l1_size,
-            model.l2_size,
-        ]:
-            # number of neurons
-            self.buf.extend(k.to_bytes(4, byteorder='little', signed=False))
+        self.buf.append(ACTIVATION_CRELU)
+        self.buf.append(int(math.log2(model.weight_scale_hidden)))
+        self.buf.append(int(math.log2(model.weight_scale_output)))
+        self.buf.extend(model.num_buckets.to_bytes(4, byteorder='little', signed=False))
+
+        self.buf.extend(model.num_features.to_bytes(4, byteorder='little', signed=False))
+        self.buf.extend(model.l1_size.to_bytes(4, byteorder='little', signed=False))
+
+        # the trainer uses the same layers in every bucket
+        stack_sizes = [*model.hidden_sizes, 1]
+        for bucket in range(model.num_buckets):
+            for k in [len(stack_sizes)] + stack_sizes:
+                # number of layers and number of neurons
+                self.buf.extend(k.to_bytes(4, byteorder='little', signed=False))
 
         self.write_linear(
             model.l1,
@@ -25,30 +57,45 @@ class NnueWriter:
             biasScale=model.quantized_one
         )
 
-        self.write_linear(
-            model.l2,
-            weightType=torch.int8,
-            weightScale=model.weight_scale_hidden,
-            weightOrder='C', # row-major
-            biasType=torch.int32,
-            biasScale=model.weight_scale_hidden * model.quantized_one
-        )
-    
-        self.write_linear(
-            model.output,
-            weightType=torch.int8,
-            weightScale=model.weight_scale_output * model.nnue2score / model.quantized_one,
-            weightOrder='C', # (does not matter, a dimension is 1)
-            biasType=torch.int32,
-            biasScale=model.weight_scale_output * model.nnue2score
-        )
+        for bucket in range(model.num_buckets):
+            for layer in [model.l2, *model.extra]:
+                self.write_linear(
+                    layer,
+                    weightType=torch.int8,
+                    weightScale=model.weight_scale_hidden,
+                    weightOrder='C', # row-major
+                    biasType=torch.int32,
+                    biasScale=model.weight_scale_hidden * model.quantized_one,
+                    bucket=bucket,
+                    num_buckets=model.num_buckets
+                )
+
+            self.write_linear(
+                model.output,
+                weightType=torch.int8,
+                weightScale=model.weight_scale_output * model.nnue2score / model.quantized_one,
+                weightOrder='C', # (does not matter, a dimension is 1)
+                biasType=torch.int32,
+                biasScale=model.weight_scale_output * model.nnue2score,
+                bucket=bucket,
+                num_buckets=model.num_buckets
+            )
+
+        header = bytearray(MAGIC)
+        header.extend(VERSION.to_bytes(4, byteorder='little', signed=False))
+        header.extend(zlib.crc32(self.buf).to_bytes(4, byteorder='little', signed=False))
+        self.buf[0:0] = header
+
+    def write_linear(self, layer, weightType, weightScale, weightOrder, biasType, biasScale, bucket=0, num_buckets=1):
+        # the outputs of every bucket are stacked in the layer
+        size = layer.out_features // num_buckets
+        outputs = slice(bucket * size, (bucket + 1) * size)
 
-    def write_linear(self, layer, weightType, weightScale, weightOrder, biasType, biasScale):
-        weight = layer.weight.data
+        weight = layer.weight.data[outputs]
         weight = weight.mul(weightScale).round().to(weightType)
         self.write_tensor(weight, weightOrder)
 
-        bias = layer.bias.data
+        bias = layer.bias.data[outputs]
         bias = bias.mul(biasScale).round().to(biasType)
         self.write_tensor(bias)
 
@@ -32,13 +32,13 @@ with open("pqr_eval.csv", "w+") as f:
     f.write("p,q,r\n")
 
     for _ in tqdm(range(5)):
-        X, y = samples.next_batch()
+        X, buckets, y = samples.next_batch()
     
         # Expand into floats
         X = expand_batch(X, chessmodel.num_features)
 
         # Forward pass
-        outputs = chessmodel(X)
+        outputs = chessmodel(X, buckets)
 
         # To P,Q,R
         output = outputs.reshape(-1, 3)
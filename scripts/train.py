@@ -26,7 +26,9 @@ def train(config: dict, use_wandb: bool):
     chessmodel = NnueModel(
         num_features=config.num_features,
         l1_size=config.l1_size,
-        l2_size=config.l2_size
+        l2_size=config.l2_size,
+        extra_hidden_sizes=config.extra_hidden_sizes,
+        num_buckets=config.output_buckets
     )
     if config.checkpoint is not None:
         print(f"Loading checkpoint from {config.checkpoint}")
@@ -44,6 +46,7 @@ def train(config: dict, use_wandb: bool):
         feature_set=config.feature_set,
         method=config.method,
         random_skipping=0.3,
+        output_buckets=config.output_buckets,
     )
     val_samples = BatchLoader(
         batch_size=config.batch_size,
@@ -52,6 +55,7 @@ def train(config: dict, use_wandb: bool):
         input_length=VALIDATION_BYTES,
         feature_set=config.feature_set,
         method=config.method,
+        output_buckets=config.output_buckets,
     )
 
     # loss function
@@ -66,7 +70,7 @@ def train(config: dict, use_wandb: bool):
     # scheduler = torch.optim.lr_scheduler.CyclicLR(optimizer, base_lr=0.000001, max_lr=0.0002, step_size_up=128)
 
 
-    def forward_loss(X: torch.Tensor, y: torch.Tensor) -> torch.Tensor:
+    def forward_loss(X: torch.Tensor, buckets: torch.Tensor, y: torch.Tensor) -> torch.Tensor:
         """
         Takes a compressed batch and computes the loss
         """
@@ -74,7 +78,7 @@ def train(config: dict, use_wandb: bool):
         X = expand_batch(X, config.num_features)
 
         # Forward pass
-        outputs = chessmodel(X)
+        outputs = chessmodel(X, buckets)
 
         # Compute the loss
         loss = loss_fn(outputs, y)
@@ -82,7 +86,7 @@ def train(config: dict, use_wandb: bool):
         return loss
 
     @torch.compile
-    def train_pass(X: torch.Tensor, y: torch.Tensor) -> float:
+    def train_pass(X: torch.Tensor, buckets: torch.Tensor, y: torch.Tensor) -> float:
         """
         Train a single batch
         Returns the loss for the batch
@@ -91,7 +95,7 @@ def train(config: dict, use_wandb: bool):
         optimizer.zero_grad()
 
         # Compute loss
-        loss = forward_loss(X, y)
+        loss = forward_loss(X, buckets, y)
         loss.backward()
 
         # Update the parameters
@@ -118,9 +122,9 @@ def train(config: dict, use_wandb: bool):
         chessmodel.train()
 
         for _ in tqdm(range(batches_per_epoch), desc=f'Epoch {epoch}/{config.epochs}'):
-            X, y = train_samples.next_batch()
+            X, buckets, y = train_samples.next_batch()
 
-            train_sum += train_pass(X, y)
+            train_sum += train_pass(X, buckets, y)
 
             if math.isnan(train_sum):
                 raise ValueError("NaN detected in training loss")
@@ -139,8 +143,8 @@ def train(config: dict, use_wandb: bool):
             if batch is None:
                 break
 
-            X, y = batch
-            val_sum += forward_loss(X, y).item()
+            X, buckets, y = batch
+            val_sum += forward_loss(X, buckets, y).item()
             val_count += 1
 
         return (
@@ -224,6 +228,8 @@ def main():
     parser.add_argument("--feature_set", default="all", type=str)
     parser.add_argument("--l1_size", default=512, type=int)
     parser.add_argument("--l2_size", default=32, type=int)
+    parser.add_argument("--output_buckets", default=1, type=int, help="Number of layer stacks after L1, selected by the number of pieces")
+    parser.add_argument("--extra_hidden_sizes", default=[], type=int, nargs="*", help="Sizes of the hidden layers after L2, e.g. 32 for →32→32→1")
 
     # training
     parser.add_argument("--checkpoint", default=None, type=str, help="Path to a .pth checkpoint to resume training")
@@ -251,7 +257,9 @@ def main():
 
     # compute feature size from feature set
     config.num_features = get_feature_set_size(config.feature_set)
-    config.arch = f"{config.method}_{config.batch_size}_({config.feature_set}[{config.num_features}]→{config.l1_size})x2→{config.l2_size}→1"
+    config.arch = f"{config.method}_{config.batch_size}_({config.feature_set}[{config.num_features}]→{config.l1_size})x2→{'→'.join(map(str, [config.l2_size, *config.extra_hidden_sizes]))}→1"
+    if config.output_buckets > 1:
+        config.arch += f"x{config.output_buckets}"
     config.arch = str(config.run) + "-" + config.arch
 
     print(config)
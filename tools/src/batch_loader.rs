@@ -41,6 +41,10 @@ pub struct BatchLoaderCommand {
     #[arg(long)]
     feature_set: String,
 
+    /// Number of output buckets of the network, the bucket of each position is written after its features
+    #[arg(long, default_value = "1")]
+    output_buckets: usize,
+
     /// Number of samples in one batch
     #[arg(long, default_value = "16384")]
     batch_size: usize,
@@ -91,6 +95,10 @@ pub fn batch_loader(cmd: BatchLoaderCommand) -> Result<(), Box<dyn Error>> {
         cmd.input_offset + cmd.input_length <= file_length,
         "Length is out of bounds"
     );
+    assert!(
+        cmd.output_buckets > 0,
+        "There must be at least one output bucket"
+    );
 
     // length of the subfile to read from
     let readable_length = if cmd.input_length == 0 {
@@ -284,7 +292,11 @@ fn smart_filter(sample: &Sample, method: &Method) -> bool {
 
 fn build_method(cmd: &BatchLoaderCommand) -> Box<dyn SampleEncoder> {
     match cmd.method {
-        Method::PQR => Box::new(PQREncoding {}),
-        Method::Eval => Box::new(EvalEncoding {}),
+        Method::PQR => Box::new(PQREncoding {
+            num_buckets: cmd.output_buckets,
+        }),
+        Method::Eval => Box::new(EvalEncoding {
+            num_buckets: cmd.output_buckets,
+        }),
     }
 }
@@ -4,7 +4,7 @@ use nn::{
     nnue::{accumulator::NnueAccumulator, model::NnueModel},
 };
 use shakmaty::{fen::Fen, CastlingMode, Chess, Position};
-use std::{cell::RefCell, rc::Rc};
+use std::sync::Arc;
 
 #[derive(Args)]
 pub struct InfoCommand {
@@ -32,7 +32,7 @@ pub fn info(cmd: InfoCommand) {
         if let Some(ref fen) = cmd.fen {
             let position: Chess = Fen::from_ascii(fen.as_bytes())
                 .unwrap()
-                .into_position(CastlingMode::Standard)
+                .into_position(CastlingMode::Chess960)
                 .unwrap();
 
             let mut features = vec![];
@@ -71,17 +71,17 @@ pub fn info(cmd: InfoCommand) {
     if let Some(fen) = cmd.fen {
         let position: Chess = Fen::from_ascii(fen.as_bytes())
             .unwrap()
-            .into_position(CastlingMode::Standard)
+            .into_position(CastlingMode::Chess960)
             .unwrap();
 
         if let Some(nn_file) = cmd.nn {
             let model = NnueModel::load(&nn_file).unwrap();
-            let mut accum = NnueAccumulator::new(Rc::new(RefCell::new(model)));
+            let mut accum = NnueAccumulator::new(Arc::new(model));
 
             accum.refresh(&position, shakmaty::Color::White);
             accum.refresh(&position, shakmaty::Color::Black);
 
-            let eval = accum.forward(position.turn());
+            let eval = accum.forward(position.board(), position.turn());
 
             // print evaluation
             println!("{}", eval);
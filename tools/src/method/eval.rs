@@ -3,7 +3,10 @@ use crate::pos_encoding::{encode_position, encoded_size};
 use nn::feature_set::FeatureSet;
 use std::io::Write;
 
-pub struct EvalEncoding;
+pub struct EvalEncoding {
+    /// Number of output buckets of the network being trained
+    pub num_buckets: usize,
+}
 
 impl SampleEncoder for EvalEncoding {
     fn x_size(&self, feature_set: &FeatureSet) -> usize {
@@ -21,7 +24,7 @@ impl SampleEncoder for EvalEncoding {
         write_y: &mut dyn Write,
         feature_set: &FeatureSet,
     ) {
-        encode_position(&sample.position, feature_set, write_x);
+        encode_position(&sample.position, feature_set, self.num_buckets, write_x);
 
         // side to move score
         write_y
@@ -7,7 +7,10 @@ use std::io::Write;
 
 const P: u32 = 0; // 0, 25, 50, 75
 
-pub struct PQREncoding;
+pub struct PQREncoding {
+    /// Number of output buckets of the network being trained
+    pub num_buckets: usize,
+}
 
 impl SampleEncoder for PQREncoding {
     fn x_size(&self, feature_set: &FeatureSet) -> usize {
@@ -60,9 +63,9 @@ impl SampleEncoder for PQREncoding {
         assert_eq!(p_position.turn().other(), q_position.turn());
         assert_eq!(p_position.turn().other(), r_position.turn());
 
-        encode_position(&p_position, feature_set, write_x);
-        encode_position(&q_position, feature_set, write_x);
-        encode_position(&r_position, feature_set, write_x);
+        encode_position(&p_position, feature_set, self.num_buckets, write_x);
+        encode_position(&q_position, feature_set, self.num_buckets, write_x);
+        encode_position(&r_position, feature_set, self.num_buckets, write_x);
     }
 }
 
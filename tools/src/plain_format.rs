@@ -88,9 +88,10 @@ impl<R: BufRead + Seek> PlainReader<R> {
             cursor.read_until(b',', &mut fen_bytes)?;
             fen_bytes.pop(); // remove trailing comma
 
+            // Chess960 castling rights are a superset of the standard ones
             Fen::from_ascii(fen_bytes.as_slice())
                 .unwrap()
-                .into_position(CastlingMode::Standard)
+                .into_position(CastlingMode::Chess960)
                 .unwrap()
         };
 
@@ -162,6 +163,8 @@ pub struct PlainWriter<W: Write + Seek> {
     is_first: bool,
     last_pos: Option<Chess>,
     last_bestmove: Option<Move>,
+    /// How castling moves are written in the current line, detected from its first position
+    castling_mode: CastlingMode,
 }
 
 impl<'a> PlainWriter<BufWriter<File>> {
@@ -175,6 +178,7 @@ impl<'a> PlainWriter<BufWriter<File>> {
             is_first: true,
             last_pos: None,
             last_bestmove: None,
+            castling_mode: CastlingMode::Standard,
         })
     }
 }
@@ -188,6 +192,7 @@ impl<W: Write + Seek> PlainWriter<W> {
             is_first: true,
             last_pos: None,
             last_bestmove: None,
+            castling_mode: CastlingMode::Standard,
         }
     }
 
@@ -216,7 +221,7 @@ impl<W: Write + Seek> PlainWriter<W> {
                 write!(
                     self.writer,
                     ",{},",
-                    UciMove::from_move(&playedmove, CastlingMode::Standard)
+                    UciMove::from_move(&playedmove, self.castling_mode)
                 )?
             }
         } else {
@@ -227,6 +232,7 @@ impl<W: Write + Seek> PlainWriter<W> {
                 write!(self.writer, "\n")?;
             }
             self.is_first = false;
+            self.castling_mode = castling_mode(&sample.position);
 
             write!(
                 self.writer,
@@ -240,7 +246,7 @@ impl<W: Write + Seek> PlainWriter<W> {
             self.writer,
             "{},{}",
             sample.score,
-            UciMove::from_move(&sample.bestmove, CastlingMode::Standard)
+            UciMove::from_move(&sample.bestmove, self.castling_mode)
         )?;
 
         // store last
@@ -260,6 +266,13 @@ impl<W: Write + Seek> PlainWriter<W> {
     }
 }
 
+/// Castling moves are written as king to rook only in Chess960 positions,
+/// so files of standard chess stay the same.
+/// It clones the position, so it is only called at the start of each line
+fn castling_mode(pos: &Chess) -> CastlingMode {
+    CastlingMode::detect(&pos.clone().into_setup(EnPassantMode::Always))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -350,7 +363,7 @@ mod tests {
         fn make_sample(fen: &str, bestmove: &str, score: i32) -> Sample {
             let position = Fen::from_ascii(fen.as_bytes())
                 .unwrap()
-                .into_position(CastlingMode::Standard)
+                .into_position(CastlingMode::Chess960)
                 .unwrap();
 
             let bestmove = UciMove::from_ascii(bestmove.as_bytes())
@@ -374,6 +387,18 @@ mod tests {
             make_sample("8/2k5/p1P5/5r2/2K5/8/P7/7R b - - 4 39", "f5f7", -1),
             make_sample("8/3kn3/p7/3P3p/3K2p1/P5B1/6PP/8 b - - 2 31", "h5h4", -730),
             make_sample("8/5k2/1p5r/p1pPR3/P1P3K1/8/8/8 w - - 2 57", "g4g5", 212),
+            // castling
+            make_sample("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1", 35),
+            make_sample(
+                "1r3kr1/pppppppp/8/8/8/8/PPPPPPPP/1R3KR1 w GBgb - 0 1",
+                "f1g1",
+                20,
+            ),
+            make_sample(
+                "rn2k1r1/pppppppp/8/8/8/8/PPPPPPPP/RN2K1R1 b GAga - 0 1",
+                "e8g8",
+                -15,
+            ),
         ]
     }
 }
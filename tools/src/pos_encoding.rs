@@ -1,4 +1,5 @@
 use nn::feature_set::FeatureSet;
+use nn::nnue::model::output_bucket;
 use shakmaty::Board;
 use shakmaty::Chess;
 use shakmaty::Color;
@@ -7,18 +8,27 @@ use std::io::Write;
 
 /// Returns the size of the encoded position in bytes given a feature set
 pub fn encoded_size(feature_set: &FeatureSet) -> usize {
-    2 * (feature_set.num_features() as usize).div_ceil(64) * 8
+    (2 * (feature_set.num_features() as usize).div_ceil(64) + 1) * 8
 }
 
 /// Encodes a position (features of both POVs) into a compacted (u64) tensor buffer.
-/// First the side to move, then the other
-pub fn encode_position(position: &Chess, feature_set: &FeatureSet, write: &mut dyn Write) {
+/// First the side to move, then the other, followed by the output bucket of the position (u64)
+pub fn encode_position(
+    position: &Chess,
+    feature_set: &FeatureSet,
+    num_buckets: usize,
+    write: &mut dyn Write,
+) {
     let turn = position.turn();
     let board = position.board();
 
     // encode first side to move, then the other
     encode_side(board, turn, turn, feature_set, write);
     encode_side(board, turn, turn.other(), feature_set, write);
+
+    // bucket used by the trainer to select the layer stack
+    let bucket = output_bucket(board, num_buckets) as u64;
+    write.write_all(&bucket.to_le_bytes()).unwrap();
 }
 
 /// Encodes a side (features of a single POV) into a compacted (u64) tensor buffer
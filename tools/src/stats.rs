@@ -7,7 +7,10 @@ use shakmaty::{attacks, Bitboard, Color, File, Position, Rank, Role};
 use std::io::Write;
 use std::{collections::HashMap, fs, io::BufWriter};
 
-const FEATURE_SETS: [&str; 9] = ["all", "h", "v", "d1", "d2", "ph", "pv", "mb", "mc"];
+const FEATURE_SETS: [&str; 15] = [
+    "all", "h", "v", "d1", "d2", "ph", "pv", "mb", "mc", "hkp", "hka", "hkp8", "hka8", "threats",
+    "ps",
+];
 
 #[derive(Args)]
 pub struct StatsCommand {